@@ -8,6 +8,7 @@ _start:
 	csrr	t0 , mhartid
 	bnez	t0 , wait
 	csrw	satp , zero	# No virtual address translation
+	mv	s1 , a1		# Firmware passes the DTB pointer in a1, keep it for kinit
 
 .option	push
 .option norelax	# No relaxation optimizations
@@ -28,7 +29,8 @@ bss2:
 	la	sp , _stack	# Setup stack
 	li	t0 , (0b11 << 11) | (1 << 7) | (1 << 3) 	# Set 12-th , 11-th bits
 	csrw	mstatus , t0 
-	la	    t1 , kINIT
+	mv	    a0 , s1	# kinit(dtb)
+	la	    t1 , kinit
 	csrw	mepc , t1
 	la	    t2 , asm_trap_vector
 	csrw	mtvec , t2
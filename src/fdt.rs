@@ -0,0 +1,311 @@
+// Minimal reader for the flattened device tree (DTB) that firmware / QEMU passes in a1.
+// We only need enough of it to find out where RAM is and what we must not touch.
+// All values in the blob are big endian.
+
+const FDT_MAGIC: u32 = 0xd00d_feed ;
+const FDT_BEGIN_NODE: u32 = 0x1 ;
+const FDT_END_NODE: u32 = 0x2 ;
+const FDT_PROP: u32 = 0x3 ;
+const FDT_NOP: u32 = 0x4 ;
+const FDT_END: u32 = 0x9 ;
+
+// Deepest node nesting we keep #address-cells / #size-cells for
+const MAX_DEPTH: usize = 16 ;
+
+// Size of the header fields we read, up to size_dt_struct (version 17)
+const HEADER_SIZE: usize = 40 ;
+
+pub struct Fdt{
+    base: usize ,
+    total_size: usize ,
+    struct_off: usize ,
+    struct_size: usize ,
+    strings_off: usize ,
+    strings_size: usize ,
+    rsvmap_off: usize ,
+}
+
+// Read a big endian u32 at base + off
+fn be32(base: usize , off: usize) -> u32{
+    unsafe{
+        u32::from_be(((base + off) as *const u32).read_unaligned())
+    }
+}
+
+fn be64(base: usize , off: usize) -> u64{
+    ((be32(base , off) as u64) << 32) | be32(base , off + 4) as u64
+}
+
+// The null terminated string at base + off, None if it doesn't end before base + end
+fn c_str(base: usize , off: usize , end: usize) -> Option<&'static [u8]>{
+    let mut len = 0 ;
+    unsafe{
+        while off + len < end && *((base + off + len) as *const u8) != 0{
+            len += 1 ;
+        }
+    }
+    if off + len >= end{
+        return None ;
+    }
+    unsafe{
+        Some(core::slice::from_raw_parts((base + off) as *const u8 , len))
+    }
+}
+
+// be32 at off if all 4 bytes are before end
+fn be32_within(base: usize , off: usize , end: usize) -> Option<u32>{
+    if off.checked_add(4)? > end{
+        return None ;
+    }
+    Some(be32(base , off))
+}
+
+// The value of a #address-cells / #size-cells property of len bytes at base + off. More than 4 cells
+// (128 bits) is no address or size anybody uses, a blob that says so is broken.
+fn cell_count(base: usize , off: usize , len: usize) -> Option<usize>{
+    let n = be32_within(base , off , off + len)? as usize ;
+    if n > 4{
+        return None ;
+    }
+    Some(n)
+}
+
+// Read a "cells" sized big endian number (1 or 2 cells)
+fn read_cells(base: usize , off: usize , cells: usize) -> usize{
+    match cells{
+        0 => 0 ,
+        1 => be32(base , off) as usize ,
+        _ => be64(base , off + (cells - 2) * 4) as usize ,
+    }
+}
+
+// Node name without the unit address, "memory@80000000" -> "memory"
+fn base_name(name: &[u8]) -> &[u8]{
+    let mut i = 0 ;
+    while i < name.len() && name[i] != b'@'{
+        i += 1 ;
+    }
+    &name[..i]
+}
+
+impl Fdt{
+    // Returns None if there is no DTB at addr, or it is malformed: every offset and size is checked
+    // against the header and both blocks are walked once here, so the for_each_ functions stay inside.
+    pub fn from_addr(addr: usize) -> Option<Fdt>{
+        if addr == 0 || !addr.is_multiple_of(4) || be32(addr , 0) != FDT_MAGIC{
+            return None ;
+        }
+        let total_size = be32(addr , 4) as usize ;
+        // size_dt_struct came with version 17, the last compatible version has to know it too
+        if total_size < HEADER_SIZE || be32(addr , 20) < 17 || be32(addr , 24) > 17{
+            return None ;
+        }
+        let fdt = Fdt{
+            base: addr ,
+            total_size ,
+            struct_off: be32(addr , 8) as usize ,
+            struct_size: be32(addr , 36) as usize ,
+            strings_off: be32(addr , 12) as usize ,
+            strings_size: be32(addr , 32) as usize ,
+            rsvmap_off: be32(addr , 16) as usize ,
+        } ;
+        let inside = |off: usize , size: usize| off >= HEADER_SIZE && off + size <= total_size ;
+        if !inside(fdt.struct_off , fdt.struct_size) || !inside(fdt.strings_off , fdt.strings_size) || !inside(fdt.rsvmap_off , 16){
+            return None ;
+        }
+        fdt.walk_rsvmap(&mut |_ , _| {})? ;
+        fdt.walk_reg(|_ , _ , _| false , &mut |_ , _| {})? ;
+        Some(fdt)
+    }
+
+    // Physical range occupied by the blob itself
+    pub fn range(&self) -> (usize , usize){
+        (self.base , self.base + self.total_size)
+    }
+
+    // Call f(start , size) for every range of the /memory nodes
+    pub fn for_each_memory<F: FnMut(usize , usize)>(&self , mut f: F){
+        // from_addr walked the blob already, this can't stop early
+        let _ = self.walk_reg(|depth , name , _parent| {
+            depth == 1 && name == b"memory"
+        } , &mut f) ;
+    }
+
+    // Call f(start , size) for the memory reservation block and every child of /reserved-memory
+    pub fn for_each_reserved<F: FnMut(usize , usize)>(&self , mut f: F){
+        let _ = self.walk_rsvmap(&mut f) ;
+        let _ = self.walk_reg(|depth , _name , parent| {
+            depth == 2 && parent == b"reserved-memory"
+        } , &mut f) ;
+    }
+
+    // The memory reservation block, up to its (0 , 0) entry. None if that isn't inside the blob.
+    fn walk_rsvmap<F: FnMut(usize , usize)>(&self , f: &mut F) -> Option<()>{
+        let mut off = self.rsvmap_off ;
+        loop{
+            if off + 16 > self.total_size{
+                return None ;
+            }
+            let start = be64(self.base , off) as usize ;
+            let size = be64(self.base , off + 8) as usize ;
+            if start == 0 && size == 0{
+                return Some(()) ;
+            }
+            f(start , size) ;
+            off += 16 ;
+        }
+    }
+
+    // Walk the structure block and hand every "reg" entry of the nodes selected by want(depth , name , parent name) to f.
+    // The root is depth 0. reg is decoded with the #address-cells / #size-cells of the parent node.
+    // None if a token, name or value runs past the structure or strings block, or the cell counts make no sense.
+    fn walk_reg<W , F>(&self , want: W , f: &mut F) -> Option<()>
+    where
+        W: Fn(usize , &[u8] , &[u8]) -> bool ,
+        F: FnMut(usize , usize) ,
+    {
+        let base = self.base ;
+        let struct_end = self.struct_off + self.struct_size ;
+        let strings_end = self.strings_off + self.strings_size ;
+        let mut off = self.struct_off ;
+
+        // cells[n] is the (#address-cells , #size-cells) a node at depth n decodes its reg with, set by its parent
+        let mut cells = [(2usize , 1usize) ; MAX_DEPTH] ;
+        let mut names: [&[u8] ; MAX_DEPTH] = [b"" ; MAX_DEPTH] ;
+        let mut depth: usize = 0 ;
+
+        loop{
+            let token = be32_within(base , off , struct_end)? ;
+            off += 4 ;
+            match token{
+                FDT_BEGIN_NODE => {
+                    let name = c_str(base , off , struct_end)? ;
+                    off = (off + name.len() + 1 + 3) & !3 ;
+                    depth += 1 ;
+                    if depth <= MAX_DEPTH{
+                        names[depth - 1] = base_name(name) ;
+                        if depth < MAX_DEPTH{
+                            cells[depth] = (2 , 1) ;  // Defaults for our children
+                        }
+                    }
+                }
+                FDT_END_NODE => {
+                    if depth == 0{
+                        return Some(()) ;
+                    }
+                    depth -= 1 ;
+                }
+                FDT_PROP => {
+                    let len = be32_within(base , off , struct_end)? as usize ;
+                    let name_off = be32_within(base , off + 4 , struct_end)? as usize ;
+                    let name = c_str(base , self.strings_off.checked_add(name_off)? , strings_end)? ;
+                    let value = off + 8 ;
+                    if value.checked_add(len)? > struct_end{
+                        return None ;
+                    }
+                    off = (value + len + 3) & !3 ;
+                    if depth == 0 || depth > MAX_DEPTH{
+                        continue ;
+                    }
+
+                    // The node sits at depth - 1 in the arrays
+                    let node = depth - 1 ;
+                    if name == b"#address-cells" && depth < MAX_DEPTH{
+                        cells[depth].0 = cell_count(base , value , len)? ;
+                    }
+                    else if name == b"#size-cells" && depth < MAX_DEPTH{
+                        cells[depth].1 = cell_count(base , value , len)? ;
+                    }
+                    else if name == b"reg"{
+                        let parent = if node > 0 { names[node - 1] } else { b"" } ;
+                        if !want(node , names[node] , parent){
+                            continue ;
+                        }
+                        let (addr_cells , size_cells) = cells[node] ;
+                        let entry = (addr_cells + size_cells) * 4 ;
+                        if entry == 0{
+                            continue ;
+                        }
+                        let mut i = 0 ;
+                        while i + entry <= len{
+                            let start = read_cells(base , value + i , addr_cells) ;
+                            let size = read_cells(base , value + i + addr_cells * 4 , size_cells) ;
+                            f(start , size) ;
+                            i += entry ;
+                        }
+                    }
+                }
+                FDT_NOP => {}
+                FDT_END => return Some(()) ,
+                _ => return None ,  // Corrupt blob
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::* ;
+    use crate::test_util::dtb_blob ;
+
+    const MEM: (usize , usize) = (0x8000_0000 , 0x800_0000) ;
+    const RSV: (usize , usize) = (0x8010_0000 , 0x2_0000) ;
+
+    type Ranges = Vec<(usize , usize)> ;
+
+    // The /memory and reserved ranges of blob, None if from_addr turns it down
+    fn parse(blob: &[u8]) -> Option<(Ranges , Ranges)>{
+        // from_addr wants the blob 4 byte aligned
+        let mut words = vec![0u32 ; blob.len().div_ceil(4)] ;
+        unsafe{
+            core::ptr::copy_nonoverlapping(blob.as_ptr() , words.as_mut_ptr() as *mut u8 , blob.len()) ;
+        }
+        let fdt = Fdt::from_addr(words.as_ptr() as usize)? ;
+        let (mut mem , mut rsv) = (Vec::new() , Vec::new()) ;
+        fdt.for_each_memory(|start , size| mem.push((start , size))) ;
+        fdt.for_each_reserved(|start , size| rsv.push((start , size))) ;
+        Some((mem , rsv))
+    }
+
+    fn set_be32(blob: &mut [u8] , off: usize , value: u32){
+        blob[off..off + 4].copy_from_slice(&value.to_be_bytes()) ;
+    }
+
+    // Offset of the first "reg" property token in blob
+    fn reg_prop(blob: &[u8]) -> usize{
+        let prop = [3u32 , 16 , 27].map(u32::to_be_bytes).concat() ;
+        blob.windows(prop.len()).position(|w| w == &prop[..]).unwrap()
+    }
+
+    #[test]
+    fn reads_memory_and_reserved_ranges(){
+        assert_eq!(parse(&dtb_blob(MEM , RSV)) , Some((vec![MEM] , vec![RSV]))) ;
+    }
+
+    #[test]
+    fn malformed_blobs_are_refused(){
+        let good = dtb_blob(MEM , RSV) ;
+        let reg = reg_prop(&good) ;
+        let breakages: [(usize , u32) ; 6] = [
+            (4 , good.len() as u32 - 8) ,     // totalsize cuts the strings short
+            (36 , 0x10_0000) ,                // size_dt_struct runs past the blob
+            (36 , reg as u32 - 56 + 8) ,      // The structure block ends inside a property
+            (16 , good.len() as u32) ,        // The memory reservation block is past the end
+            (reg + 4 , 0x1000) ,              // reg is longer than the structure block
+            (reg + 8 , 0x1000) ,              // The property name is past the strings
+        ] ;
+        for (off , value) in breakages{
+            let mut blob = good.clone() ;
+            set_be32(&mut blob , off , value) ;
+            assert_eq!(parse(&blob) , None , "{:#x} at {}" , value , off) ;
+        }
+
+        // A token we don't know, and #address-cells = 9
+        let mut blob = good.clone() ;
+        set_be32(&mut blob , reg , 7) ;
+        assert_eq!(parse(&blob) , None) ;
+        let mut blob = good.clone() ;
+        set_be32(&mut blob , 56 + 8 + 12 , 9) ;
+        assert_eq!(parse(&blob) , None) ;
+    }
+}
@@ -1,187 +1,1251 @@
-use crate::page::{align_val, zero_alloc, Table, PAGE_SIZE};
-use core::{mem::size_of, ptr::null_mut};
+use crate::page::{align_val, zero_alloc_internal, PageHeap, Table, PAGE_ORDER, PAGE_SIZE};
+use crate::oom::{self, AllocFailure};
+use crate::slab;
+use crate::lock::{Mutex, MutexGuard};
+use core::{mem::size_of, ptr::null_mut, sync::atomic::{AtomicPtr, Ordering}};
 
-#[repr(usize)]
-enum AllocListFlags {
-    Taken = 1 << 63,
-}
-impl AllocListFlags {
-    pub fn val(self) -> usize {
-        self as usize
-    }
-}
+// Taken flag, the top bit of flags_size (sizes never get that big)
+const TAKEN: usize = 1 << (usize::BITS - 1);
 
+/// Boundary tag. Every block starts with one (the header) and ends with a copy of it
+/// (the footer), so both neighbours of a block can be found and merged without a walk.
 struct AllocList {
     pub flags_size: usize,
 }
 impl AllocList {
     pub fn is_taken(&self) -> bool {
-        self.flags_size & AllocListFlags::Taken.val() != 0
+        self.flags_size & TAKEN != 0
     }
 
     pub fn is_free(&self) -> bool {
         !self.is_taken()
     }
 
-    pub fn set_taken(&mut self) {
-        self.flags_size |= AllocListFlags::Taken.val();
+    pub fn get_size(&self) -> usize {
+        self.flags_size & !TAKEN
+    }
+}
+
+/// A free block keeps its free list links right behind its header
+struct FreeLinks {
+    next: *mut AllocList,
+    prev: *mut AllocList,
+}
+
+// Header plus footer, what a block costs on top of its data
+const TAGS: usize = 2 * size_of::<AllocList>();
+
+// Smallest block we split off: the tags and room for the free list links
+const MIN_BLOCK: usize = TAGS + size_of::<FreeLinks>();
+
+// Write the same tag into the header and footer of the block at b
+unsafe fn set_block(b: *mut AllocList, size: usize, taken: bool) {
+    let flags_size = if taken { size | TAKEN } else { size };
+    unsafe {
+        (*b).flags_size = flags_size;
+        (*footer(b)).flags_size = flags_size;
+    }
+}
+
+unsafe fn footer(b: *mut AllocList) -> *mut AllocList {
+    unsafe { (b as *mut u8).add((*b).get_size() - size_of::<AllocList>()) as *mut AllocList }
+}
+
+unsafe fn links(b: *mut AllocList) -> *mut FreeLinks {
+    unsafe { b.add(1) as *mut FreeLinks }
+}
+
+// With the debug_heap feature every taken block holds the size that was asked for and a
+// guard word between its header and its data, and guard bytes from the end of the data up
+// to its footer. Free blocks are filled with POISON_FREE behind their links, so writes
+// past the end of a buffer and writes through stale pointers can be told apart from
+// honest data by kfree and check_heap.
+#[cfg(feature = "debug_heap")]
+const RED_ZONE: usize = 8;
+#[cfg(not(feature = "debug_heap"))]
+const RED_ZONE: usize = 0;
+
+#[cfg(feature = "debug_heap")]
+const GUARD: u8 = 0xfd;
+#[cfg(feature = "debug_heap")]
+const POISON_FREE: u8 = 0x6b;
+
+// From the header of a taken block to its data
+const LEAD: usize = size_of::<AllocList>() + 2 * RED_ZONE;
+
+/// What check_heap found wrong, and where
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HeapCorruption {
+    pub addr: usize, // The block (its header), or the fencepost
+    pub what: &'static str,
+}
+
+// Fill in the size asked for and the guards of the taken block b
+#[cfg(feature = "debug_heap")]
+unsafe fn arm_guards(b: *mut AllocList, sz: usize) {
+    unsafe {
+        let data = (b as *mut u8).add(LEAD);
+        *(b.add(1) as *mut usize) = sz;
+        (b as *mut u8).add(LEAD - RED_ZONE).write_bytes(GUARD, RED_ZONE);
+        let end = footer(b) as *mut u8;
+        data.add(sz).write_bytes(GUARD, end as usize - data as usize - sz);
     }
+}
 
-    pub fn set_free(&mut self) {
-        self.flags_size &= !AllocListFlags::Taken.val();
+// Poison everything of the free block b but its tags and links
+#[cfg(feature = "debug_heap")]
+unsafe fn poison(b: *mut AllocList) {
+    unsafe {
+        let start = links(b).add(1) as *mut u8;
+        start.write_bytes(POISON_FREE, footer(b) as usize - start as usize);
     }
+}
 
-    pub fn set_size(&mut self, sz: usize) {
-        let k = self.is_taken();
-        self.flags_size = sz & !AllocListFlags::Taken.val();
-        if k {
-            self.flags_size |= AllocListFlags::Taken.val();
+#[cfg(feature = "debug_heap")]
+unsafe fn all_bytes(start: *mut u8, end: *mut u8, val: u8) -> bool {
+    let mut p = start;
+    while p < end {
+        unsafe {
+            if *p != val {
+                return false;
+            }
+            p = p.add(1);
         }
     }
+    true
+}
 
-    pub fn get_size(&self) -> usize {
-        self.flags_size & !AllocListFlags::Taken.val()
+// Sanity checks on the block at b, which must end at or before limit. The tags are
+// always checked, the guards and the poison only in a debug heap.
+unsafe fn check_block(b: *mut AllocList, limit: *mut AllocList) -> Result<(), &'static str> {
+    unsafe {
+        let size = (*b).get_size();
+        if size < MIN_BLOCK || !size.is_multiple_of(8) {
+            return Err("bad block size");
+        }
+        if size > limit as usize - b as usize {
+            return Err("block runs past the end of its chunk");
+        }
+        if (*footer(b)).flags_size != (*b).flags_size {
+            return Err("footer does not match header");
+        }
+        #[cfg(feature = "debug_heap")]
+        {
+            if (*b).is_taken() {
+                let data = (b as *mut u8).add(LEAD);
+                let end = footer(b) as *mut u8;
+                let sz = *(b.add(1) as *mut usize);
+                if sz > end as usize - data as usize {
+                    return Err("bad requested size (header overwritten)");
+                }
+                if !all_bytes(data.sub(RED_ZONE), data, GUARD) {
+                    return Err("front guard overwritten (buffer underrun)");
+                }
+                if !all_bytes(data.add(sz), end, GUARD) {
+                    return Err("back guard overwritten (buffer overrun)");
+                }
+            }
+            else if !all_bytes(links(b).add(1) as *mut u8, footer(b) as *mut u8, POISON_FREE) {
+                return Err("free block written to (use after free)");
+            }
+        }
     }
+    Ok(())
 }
 
-// This is the head of the allocation. We start here when we search for a free memory location.
-static mut KMEM_HEAD: *mut AllocList = null_mut();
-static mut KMEM_ALLOC: usize = 0;
-static mut KMEM_PAGE_TABLE: *mut Table = null_mut();
-pub fn get_head() -> *mut u8 {
-    unsafe { KMEM_HEAD as *mut u8 }
+/// Where a KmemHeap (or a slab cache) gets its memory from
+pub trait PageSource {
+    /// Contiguous run of pages aligned to 2^align_order bytes, or null
+    fn alloc_pages_aligned(&mut self, pages: usize, align_order: usize) -> *mut u8;
+    fn free_pages(&mut self, ptr: *mut u8);
+
+    /// Contiguous, page aligned run of pages, or null
+    fn alloc_pages(&mut self, pages: usize) -> *mut u8 {
+        self.alloc_pages_aligned(pages, PAGE_SIZE.trailing_zeros() as usize)
+    }
 }
 
-pub fn get_page_table() -> *mut Table {
-    unsafe { KMEM_PAGE_TABLE as *mut Table }
+impl PageSource for PageHeap {
+    fn alloc_pages_aligned(&mut self, pages: usize, align_order: usize) -> *mut u8 {
+        self.alloc_aligned(pages, align_order)
+    }
+
+    fn free_pages(&mut self, ptr: *mut u8) {
+        self.dealloc(ptr)
+    }
 }
 
-pub fn get_num_allocations() -> usize {
-    unsafe { KMEM_ALLOC }
+/// Header at the start of every chunk of pages the heap owns. It is followed by a taken
+/// footer tag (the prologue), the blocks of the chunk back to back, and a taken header
+/// tag of size 0 at the very end (the epilogue). The two fenceposts keep merges from
+/// running off either edge of the chunk.
+struct Chunk {
+    next: *mut Chunk,
+    size: usize, // Bytes, including this header
 }
 
-pub fn init() {
-    unsafe {
-        // Allocate 64 kernel pages (64 * 4096 = 262 KiB)
-        let k_alloc = zero_alloc(64);
-        assert!(!k_alloc.is_null());
-        KMEM_ALLOC = 64;
-        KMEM_HEAD = k_alloc as *mut AllocList;
-        (*KMEM_HEAD).set_free();
-        (*KMEM_HEAD).set_size(KMEM_ALLOC * PAGE_SIZE);
-        KMEM_PAGE_TABLE = zero_alloc(1) as *mut Table;
+// Chunk header and both fenceposts
+const CHUNK_OVERHEAD: usize = size_of::<Chunk>() + 2 * size_of::<AllocList>();
+
+impl Chunk {
+    fn prologue(&self) -> *mut AllocList {
+        (self as *const Chunk as usize + size_of::<Chunk>()) as *mut AllocList
+    }
+
+    fn first(&self) -> *mut AllocList {
+        unsafe { self.prologue().add(1) }
+    }
+
+    /// The epilogue, right behind the last block
+    fn tail(&self) -> *mut AllocList {
+        (self as *const Chunk as usize + self.size - size_of::<AllocList>()) as *mut AllocList
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        let p = ptr as *mut AllocList;
+        p > self.first() && p < self.tail()
+    }
+
+    /// True if the whole chunk is a single free block
+    fn is_empty(&self) -> bool {
+        unsafe {
+            let first = self.first();
+            (*first).is_free() && (*first).get_size() == self.size - CHUNK_OVERHEAD
+        }
     }
 }
 
-pub fn kzmalloc(sz: usize) -> *mut u8 {
-    let size = align_val(sz, 3);
-    let ret = kmalloc(size);
+/// Pages a heap asks for at a time when it runs out of room
+pub const KMEM_GROW_PAGES: usize = 64;
 
-    if !ret.is_null() {
-        for i in 0..size {
+// A first-fit byte allocator with boundary tags. Every block carries its size and taken
+// flag in a header and a matching footer, and the free blocks of all chunks are linked
+// into one doubly linked free list. kmalloc only looks at free blocks, and kfree merges
+// a block with its free neighbours on the spot, so the heap never needs a coalescing pass.
+// When no free block fits the heap asks its PageSource for another chunk, and chunks that
+// become completely free again are handed back (except the first one).
+// The PageSource decides where chunks come from: KernelPages in the kernel, a PageHeap in tests.
+pub struct KmemHeap<P: PageSource> {
+    chunks: *mut Chunk,
+    // This is the head of the free list. We start here when we search for a free memory location.
+    free: *mut AllocList,
+    size: usize, // Bytes over all chunks
+    pages: P,
+    release_empty: bool,
+}
+
+impl<P: PageSource> KmemHeap<P> {
+    pub const fn new(pages: P) -> Self {
+        KmemHeap {
+            chunks: null_mut(),
+            free: null_mut(),
+            size: 0,
+            pages,
+            release_empty: true,
+        }
+    }
+
+    /// Start of the first chunk
+    pub fn get_head(&self) -> *mut u8 {
+        self.chunks as *mut u8
+    }
+
+    /// Size of the heap in bytes, over all chunks
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    /// Is ptr inside one of our chunks ?
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        let mut chunk = self.chunks;
+        while !chunk.is_null() {
             unsafe {
-                *ret.add(i) = 0;
+                if (*chunk).contains(ptr) {
+                    return true;
+                }
+                chunk = (*chunk).next;
             }
         }
+        false
     }
-    ret
-}
 
-pub fn kmalloc(sz: usize) -> *mut u8 {
-    unsafe {
-        let size = align_val(sz, 3) + size_of::<AllocList>();
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
-            as *mut AllocList;
-
-        while head < tail {
-            if (*head).is_free() && size <= (*head).get_size() {
-                let chunk_size = (*head).get_size();
-                let rem = chunk_size - size;
-                (*head).set_taken();
-                if rem > size_of::<AllocList>() {
-                    let next = (head as *mut u8).add(size)
-                        as *mut AllocList;
-                    // There is space remaining here.
-                    (*next).set_free();
-                    (*next).set_size(rem);
-                    (*head).set_size(size);
+    /// Should chunks that become completely free go back to the page source ?
+    pub fn set_release_empty(&mut self, release: bool) {
+        self.release_empty = release;
+    }
+
+    /// Call f(start, bytes) for every chunk, first chunk first
+    pub fn for_each_chunk<F: FnMut(usize, usize)>(&self, mut f: F) {
+        let mut chunk = self.chunks;
+        while !chunk.is_null() {
+            unsafe {
+                f(chunk as usize, (*chunk).size);
+                chunk = (*chunk).next;
+            }
+        }
+    }
+
+    /// Add a chunk of (at least) the given number of pages. Returns it, or null if the page source is out.
+    pub fn grow(&mut self, pages: usize) -> *mut u8 {
+        let mem = self.pages.alloc_pages(pages);
+        if mem.is_null() {
+            return null_mut();
+        }
+        unsafe {
+            let chunk = mem as *mut Chunk;
+            (*chunk).next = null_mut();
+            (*chunk).size = pages * PAGE_SIZE;
+            (*(*chunk).prologue()).flags_size = TAKEN;
+            (*(*chunk).tail()).flags_size = TAKEN;
+            let first = (*chunk).first();
+            set_block(first, (*chunk).size - CHUNK_OVERHEAD, false);
+            #[cfg(feature = "debug_heap")]
+            poison(first);
+            self.push_free(first);
+
+            // Keep the chunks in the order we got them, the first one is never released
+            if self.chunks.is_null() {
+                self.chunks = chunk;
+            }
+            else {
+                let mut last = self.chunks;
+                while !(*last).next.is_null() {
+                    last = (*last).next;
                 }
-                else {
-                    (*head).set_size(chunk_size);
+                (*last).next = chunk;
+            }
+        }
+        self.size += pages * PAGE_SIZE;
+        mem
+    }
+
+    unsafe fn push_free(&mut self, b: *mut AllocList) {
+        unsafe {
+            (*links(b)).prev = null_mut();
+            (*links(b)).next = self.free;
+            if !self.free.is_null() {
+                (*links(self.free)).prev = b;
+            }
+            self.free = b;
+        }
+    }
+
+    unsafe fn remove_free(&mut self, b: *mut AllocList) {
+        unsafe {
+            let FreeLinks { next, prev } = links(b).read();
+            if !prev.is_null() {
+                (*links(prev)).next = next;
+            }
+            else {
+                self.free = next;
+            }
+            if !next.is_null() {
+                (*links(next)).prev = prev;
+            }
+        }
+    }
+
+    pub fn kzmalloc(&mut self, sz: usize) -> *mut u8 {
+        self.kzmalloc_aligned(sz, 8)
+    }
+
+    pub fn kzmalloc_aligned(&mut self, sz: usize, align: usize) -> *mut u8 {
+        let size = align_val(sz, 3);
+        let ret = self.kmalloc_aligned(size, align);
+
+        if !ret.is_null() {
+            for i in 0..size {
+                unsafe {
+                    *ret.add(i) = 0;
                 }
-                return head.add(1) as *mut u8;
+            }
+        }
+        ret
+    }
+
+    pub fn kmalloc(&mut self, sz: usize) -> *mut u8 {
+        self.kmalloc_aligned(sz, 8)
+    }
+
+    /// Allocate sz bytes at an address that is a multiple of align (a power of two).
+    /// If the first free block that fits isn't aligned the way we need, the gap in front
+    /// of the allocation is split off as a free block of its own instead of being wasted.
+    /// Grows the heap if no free block has room.
+    pub fn kmalloc_aligned(&mut self, sz: usize, align: usize) -> *mut u8 {
+        assert!(align.is_power_of_two());
+        // Headers are 8 bytes and every block is a multiple of 8, so that much we get for free
+        let align = align.max(8);
+        // A debug heap adds the front guard, the requested size and at least RED_ZONE
+        // guard bytes at the back
+        let size = (align_val(sz, 3) + TAGS + 3 * RED_ZONE).max(MIN_BLOCK);
+
+        let mut ret = unsafe { self.alloc_fit(size, align) };
+        if ret.is_null() {
+            ret = self.grow_for(size, align);
+        }
+        #[cfg(feature = "debug_heap")]
+        if !ret.is_null() {
+            unsafe { arm_guards(ret.sub(LEAD) as *mut AllocList, sz) };
+        }
+        ret
+    }
+
+    /// Add a chunk big enough for a block of size bytes at alignment align and allocate it there
+    fn grow_for(&mut self, size: usize, align: usize) -> *mut u8 {
+        // Nothing fits, get a chunk that surely does (header, worst case alignment gap, block)
+        let need = CHUNK_OVERHEAD + align + MIN_BLOCK + size;
+        let pages = need.div_ceil(PAGE_SIZE).max(KMEM_GROW_PAGES);
+        let mut mem = self.grow(pages);
+        if mem.is_null() && pages > KMEM_GROW_PAGES {
+            return null_mut();
+        }
+        if mem.is_null() {
+            // Memory is tight, try with just what we need
+            mem = self.grow(need.div_ceil(PAGE_SIZE));
+            if mem.is_null() {
+                return null_mut();
+            }
+        }
+        // The new chunk's block is at the front of the free list
+        unsafe { self.alloc_fit(size, align) }
+    }
+
+    /// First fit of size bytes (tags included) at data alignment align on the free list
+    unsafe fn alloc_fit(&mut self, size: usize, align: usize) -> *mut u8 {
+        unsafe {
+            let mut head = self.free;
+            while !head.is_null() {
+                let block_size = (*head).get_size();
+                let start = head as usize;
+                // First aligned data address whose header leaves either no gap or a
+                // gap big enough to hold a free block
+                let mut data = align_val(start + LEAD, align.trailing_zeros() as usize);
+                while data - LEAD != start && data - LEAD - start < MIN_BLOCK {
+                    data += align;
+                }
+                let pad = data - LEAD - start;
+
+                if pad + size <= block_size {
+                    self.remove_free(head);
+                    if pad > 0 {
+                        // The gap stays free, the allocation starts right after it
+                        set_block(head, pad, false);
+                        self.push_free(head);
+                        head = (data - LEAD) as *mut AllocList;
+                        set_block(head, block_size - pad, false);
+                    }
+                    return self.take(head, size);
+                }
+                head = (*links(head)).next;
+            }
+        }
+        null_mut()
+    }
+
+    /// Mark the block at head (already off the free list) as taken, splitting off
+    /// whatever is left after size bytes as a new free block
+    unsafe fn take(&mut self, head: *mut AllocList, size: usize) -> *mut u8 {
+        unsafe {
+            let block_size = (*head).get_size();
+            let rem = block_size - size;
+            if rem >= MIN_BLOCK {
+                // There is space remaining here.
+                let next = (head as *mut u8).add(size) as *mut AllocList;
+                set_block(next, rem, false);
+                self.push_free(next);
+                set_block(head, size, true);
             }
             else {
-                head = (head as *mut u8).add((*head).get_size())
-                    as *mut AllocList;
+                set_block(head, block_size, true);
             }
+            (head as *mut u8).add(LEAD)
         }
     }
-    null_mut()
-}
 
-/// Free a sub-page level allocation
-pub fn kfree(ptr: *mut u8) {
-    unsafe {
-        if !ptr.is_null() {
-            let p = (ptr as *mut AllocList).offset(-1);
-            if (*p).is_taken() {
-                (*p).set_free();
+    /// Free a sub-page level allocation
+    // Like free() in C, ptr has to be something kmalloc returned. We check that it is
+    // inside one of our chunks, and in debug builds that a taken block starts there.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn kfree(&mut self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        unsafe {
+            // Find the chunk the block lives in, we need it to hand empty chunks back
+            let mut prev: *mut Chunk = null_mut();
+            let mut chunk = self.chunks;
+            while !chunk.is_null() && !(*chunk).contains(ptr) {
+                prev = chunk;
+                chunk = (*chunk).next;
+            }
+            assert!(!chunk.is_null(), "kfree: {:p} is not in the kernel heap", ptr);
+
+            let mut p = ptr.sub(LEAD) as *mut AllocList;
+            // A double free, or a stale pointer into a block that was merged since, doesn't
+            // find a taken block with matching tags here. A debug heap always checks, other
+            // debug builds check the tags.
+            if cfg!(any(feature = "debug_heap", debug_assertions)) {
+                assert!((*p).is_taken(), "kfree: {:p} is not allocated (double free?)", ptr);
+                if let Err(what) = check_block(p, (*chunk).tail()) {
+                    panic!("kfree: {:p}: corrupt block at {:p}: {}", ptr, p, what);
+                }
+            }
+            if (*p).is_free() {
+                return;
+            }
+            let mut size = (*p).get_size();
+
+            // The fenceposts are taken, so neither merge leaves the chunk
+            let next = (p as *mut u8).add(size) as *mut AllocList;
+            if (*next).is_free() {
+                self.remove_free(next);
+                size += (*next).get_size();
+            }
+            let before = p.offset(-1); // Footer of the block in front of us
+            if (*before).is_free() {
+                p = (p as *mut u8).sub((*before).get_size()) as *mut AllocList;
+                self.remove_free(p);
+                size += (*p).get_size();
+            }
+            set_block(p, size, false);
+            #[cfg(feature = "debug_heap")]
+            poison(p);
+            self.push_free(p);
+
+            // Give a chunk that is all free again back, but always keep the first one
+            if self.release_empty && !prev.is_null() && (*chunk).is_empty() {
+                self.remove_free((*chunk).first());
+                (*prev).next = (*chunk).next;
+                self.size -= (*chunk).size;
+                self.pages.free_pages(chunk as *mut u8);
             }
-            coalesce();
         }
     }
-}
 
-/// Merge smaller chunks into a bigger chunk
-pub fn coalesce() {
-    unsafe {
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
-            as *mut AllocList;
+    /// Make the allocation at ptr new_size bytes without moving it: shrink by splitting the
+    /// tail off as a free block, or grow into the free block right behind it. Returns false
+    /// (and leaves the block alone) if there is no room to grow, the caller has to move it then.
+    // ptr has to be something kmalloc returned, as for kfree
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn resize_in_place(&mut self, ptr: *mut u8, new_size: usize) -> bool {
+        let need = (align_val(new_size, 3) + TAGS + 3 * RED_ZONE).max(MIN_BLOCK);
+        unsafe {
+            let b = ptr.sub(LEAD) as *mut AllocList;
+            assert!((*b).is_taken(), "resize: {:p} is not allocated", ptr);
+            let mut size = (*b).get_size();
+
+            if need > size {
+                let next = (b as *mut u8).add(size) as *mut AllocList;
+                if (*next).is_taken() || size + (*next).get_size() < need {
+                    return false;
+                }
+                self.remove_free(next);
+                size += (*next).get_size();
+            }
 
-        while head < tail {
-            let next = (head as *mut u8).add((*head).get_size())
-                as *mut AllocList;
-            if (*head).get_size() == 0 {
-                // If this happens, then we have a bad heap (double free or something).
-                break;
+            let rem = size - need;
+            if rem >= MIN_BLOCK {
+                // Give the tail back, merged with a free block behind it
+                let mut tail_size = rem;
+                let next = (b as *mut u8).add(size) as *mut AllocList;
+                if (*next).is_free() {
+                    self.remove_free(next);
+                    tail_size += (*next).get_size();
+                }
+                let tail = (b as *mut u8).add(need) as *mut AllocList;
+                set_block(tail, tail_size, false);
+                #[cfg(feature = "debug_heap")]
+                poison(tail);
+                self.push_free(tail);
+                size = need;
             }
-            else if next >= tail {
-                // We calculated the next by using the size given as get_size(), however this could push us past the tail.
+            set_block(b, size, true);
+            #[cfg(feature = "debug_heap")]
+            arm_guards(b, new_size);
+        }
+        true
+    }
+
+    /// Neighbouring free blocks are merged as soon as they are freed, so there is nothing
+    /// left to do here. Kept so callers from the days of the coalescing pass still build.
+    pub fn coalesce(&mut self) {}
+
+    /// Walk every block of every chunk and return the first one that is corrupt. Checks
+    /// the fenceposts and the boundary tags, that no free blocks were left unmerged and
+    /// that the free list holds the free blocks. With the debug_heap feature it also
+    /// checks the guards of taken blocks and the poison of free ones.
+    pub fn check_heap(&self) -> Result<(), HeapCorruption> {
+        let corrupt = |addr: *mut AllocList, what| Err(HeapCorruption { addr: addr as usize, what });
+        let mut free_blocks = 0;
+        let mut chunk = self.chunks;
+        while !chunk.is_null() {
+            unsafe {
+                let tail = (*chunk).tail();
+                if (*(*chunk).prologue()).flags_size != TAKEN {
+                    return corrupt((*chunk).prologue(), "chunk prologue overwritten");
+                }
+                if (*tail).flags_size != TAKEN {
+                    return corrupt(tail, "chunk epilogue overwritten");
+                }
+                let mut head = (*chunk).first();
+                let mut prev_free = false;
+                while head < tail {
+                    if let Err(what) = check_block(head, tail) {
+                        return corrupt(head, what);
+                    }
+                    if (*head).is_free() {
+                        if prev_free {
+                            return corrupt(head, "free block next to a free block");
+                        }
+                        free_blocks += 1;
+                    }
+                    prev_free = (*head).is_free();
+                    head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+                }
+                chunk = (*chunk).next;
             }
-            else if (*head).is_free() && (*next).is_free() {
-                // This means we have adjacent blocks needing to be freed. 
-                (*head).set_size( (*head).get_size() + (*next).get_size(),);
+        }
+
+        // Stop after free_blocks entries so a list that loops can't hang us
+        let mut listed = 0;
+        let mut b = self.free;
+        while !b.is_null() && listed <= free_blocks {
+            unsafe {
+                if !self.contains(b.add(1) as *mut u8) || (*b).is_taken() {
+                    return corrupt(b, "free list points at a block that isn't free");
+                }
+                b = (*links(b)).next;
             }
-            // If we get here, we might've moved. Recalculate new head.
-            head = (head as *mut u8).add((*head).get_size())
-                as *mut AllocList;
+            listed += 1;
         }
+        if listed != free_blocks {
+            return corrupt(self.free, "free list doesn't hold every free block");
+        }
+        Ok(())
     }
 }
 
-use core::alloc::{GlobalAlloc, Layout};
+/// The kernel heap's page source: the page allocator. kinit identity maps the whole
+/// page heap, so new chunks are mapped before we get them and stay mapped once freed.
+pub struct KernelPages;
+
+impl PageSource for KernelPages {
+    fn alloc_pages_aligned(&mut self, pages: usize, align_order: usize) -> *mut u8 {
+        crate::page::zero_alloc_internal(pages, align_order)
+    }
+
+    fn free_pages(&mut self, ptr: *mut u8) {
+        crate::page::dealloc(ptr)
+    }
+}
+
+// The chunk list and the free list only point into chunks the heap owns
+unsafe impl<P: PageSource + Send> Send for KmemHeap<P> {}
+
+// The kernel's byte heap, set up by init. Lock order: KMEM (or a slab cache) before
+// the page heap, never the other way round.
+static KMEM: Mutex<KmemHeap<KernelPages>> = Mutex::new(KmemHeap::new(KernelPages));
+static KMEM_PAGE_TABLE: AtomicPtr<Table> = AtomicPtr::new(null_mut());
+
+fn heap() -> MutexGuard<'static, KmemHeap<KernelPages>> {
+    KMEM.lock()
+}
+
+pub fn get_head() -> *mut u8 {
+    heap().get_head()
+}
+
+pub fn get_page_table() -> *mut Table {
+    KMEM_PAGE_TABLE.load(Ordering::Acquire)
+}
+
+/// Number of pages the kernel heap occupies, over all its chunks
+pub fn get_num_allocations() -> usize {
+    heap().get_size() / PAGE_SIZE
+}
+
+/// Call f(start, bytes) for every chunk of the kernel heap
+pub fn for_each_chunk<F: FnMut(usize, usize)>(f: F) {
+    heap().for_each_chunk(f)
+}
+
+/// Should chunks that become completely free go back to the page allocator ?
+pub fn set_release_empty(release: bool) {
+    heap().set_release_empty(release)
+}
+
+pub fn init() {
+    // Start with 64 kernel pages (64 * 4096 = 262 KiB), we grow from there
+    assert!(!heap().grow(KMEM_GROW_PAGES).is_null());
+    KMEM_PAGE_TABLE.store(zero_alloc_internal(1, PAGE_ORDER) as *mut Table, Ordering::Release);
+    // The empty slabs the size classes hold on to are the first thing to go when memory is short
+    oom::register_shrinker("slab", slab::shrink);
+}
+
+// Small requests are served by the size-class slab caches in slab.rs, anything
+// bigger (or with an odd alignment) by the list allocator above.
+// With the alloc_track feature every allocation is recorded in track.rs under the
+// location of whoever called kmalloc, so these are #[track_caller] in that mode.
+
+#[cfg_attr(feature = "alloc_track", track_caller)]
+pub fn kzmalloc(sz: usize) -> *mut u8 {
+    kzmalloc_aligned(sz, 8)
+}
+
+#[cfg_attr(feature = "alloc_track", track_caller)]
+pub fn kmalloc(sz: usize) -> *mut u8 {
+    kmalloc_aligned(sz, 8)
+}
+
+#[cfg_attr(feature = "alloc_track", track_caller)]
+pub fn kmalloc_aligned(sz: usize, align: usize) -> *mut u8 {
+    let ret = kmalloc_once(sz, align);
+    #[cfg(feature = "alloc_track")]
+    crate::track::add(ret, sz, crate::track::Kind::Kmem, core::panic::Location::caller());
+    ret
+}
+
+// One go at the slabs and the list allocator, without shrinking or tracking
+fn kmalloc_once(sz: usize, align: usize) -> *mut u8 {
+    let mut ret = null_mut();
+    // A debug heap keeps everything on the list allocator, where the guards are
+    if !cfg!(feature = "debug_heap") && slab::fits(sz, align) {
+        ret = slab::alloc(sz, align);
+    }
+    if ret.is_null() {
+        ret = heap().kmalloc_aligned(sz, align);
+    }
+    ret
+}
+
+#[cfg_attr(feature = "alloc_track", track_caller)]
+pub fn try_kmalloc(sz: usize) -> Result<*mut u8, AllocFailure> {
+    try_kmalloc_aligned(sz, 8)
+}
+
+#[cfg_attr(feature = "alloc_track", track_caller)]
+pub fn try_kzmalloc(sz: usize) -> Result<*mut u8, AllocFailure> {
+    try_kzmalloc_aligned(sz, 8)
+}
+
+#[cfg_attr(feature = "alloc_track", track_caller)]
+pub fn try_kmalloc_aligned(sz: usize, align: usize) -> Result<*mut u8, AllocFailure> {
+    let ret = try_kmalloc_untracked(sz, align)?;
+    #[cfg(feature = "alloc_track")]
+    crate::track::add(ret, sz, crate::track::Kind::Kmem, core::panic::Location::caller());
+    Ok(ret)
+}
+
+// try_kmalloc_aligned without a record in track.rs, the global allocator makes its own
+fn try_kmalloc_untracked(sz: usize, align: usize) -> Result<*mut u8, AllocFailure> {
+    if !align.is_power_of_two() || sz > isize::MAX as usize - align {
+        return Err(AllocFailure::BadLayout);
+    }
+    let ret = oom::retry(sz, || kmalloc_once(sz, align));
+    if ret.is_null() {
+        return Err(AllocFailure::OutOfMemory { size: sz, align });
+    }
+    Ok(ret)
+}
+
+#[cfg_attr(feature = "alloc_track", track_caller)]
+pub fn try_kzmalloc_aligned(sz: usize, align: usize) -> Result<*mut u8, AllocFailure> {
+    let size = align_val(sz, 3);
+    let ret = try_kmalloc_aligned(size, align)?;
+    unsafe {
+        ret.write_bytes(0, size);
+    }
+    Ok(ret)
+}
+
+#[cfg_attr(feature = "alloc_track", track_caller)]
+pub fn kzmalloc_aligned(sz: usize, align: usize) -> *mut u8 {
+    let size = align_val(sz, 3);
+    let ret = kmalloc_aligned(size, align);
+    if !ret.is_null() {
+        unsafe {
+            ret.write_bytes(0, size);
+        }
+    }
+    ret
+}
+
+/// Free a sub-page level allocation
+pub fn kfree(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    #[cfg(feature = "alloc_track")]
+    crate::track::remove(ptr, crate::track::Kind::Kmem);
+    let mut heap = heap();
+    if heap.contains(ptr) {
+        heap.kfree(ptr)
+    }
+    else {
+        drop(heap);
+        slab::free(ptr)
+    }
+}
+
+/// Make the allocation at ptr hold new_size bytes without moving it, if that can be
+/// done. Returns false if it has to move.
+pub fn resize_in_place(ptr: *mut u8, new_size: usize) -> bool {
+    let mut heap = heap();
+    let done = if heap.contains(ptr) {
+        heap.resize_in_place(ptr, new_size)
+    }
+    else {
+        drop(heap);
+        slab::resize_in_place(ptr, new_size)
+    };
+    #[cfg(feature = "alloc_track")]
+    if done {
+        crate::track::resize(ptr, crate::track::Kind::Kmem, new_size);
+    }
+    done
+}
+
+/// Nothing to do any more, kfree merges free neighbours right away
+pub fn coalesce() {
+    heap().coalesce()
+}
+
+/// Check the kernel heap (see KmemHeap::check_heap), printing the first corrupt block
+pub fn check_heap() -> Result<(), HeapCorruption> {
+    let ret = heap().check_heap();
+    if let Err(c) = ret {
+        println!("kmem: corrupt block at {:#x}: {}", c.addr, c.what);
+    }
+    ret
+}
+
+use core::alloc::{AllocError, Allocator, Layout};
+use core::ptr::NonNull;
+use crate::page::PageAllocator;
+#[cfg(not(test))]
+use core::alloc::GlobalAlloc;
+#[cfg(not(test))]
 struct OsGlobalAlloc;
 
+// GlobalAlloc::alloc can't be #[track_caller], so with alloc_track every Box and Vec would be
+// recorded at the same line in here. They go in as Kind::Global with their Layout instead.
+#[cfg(not(test))]
 unsafe impl GlobalAlloc for OsGlobalAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        kzmalloc(layout.size())
+        let ret = if layout.align() >= PAGE_SIZE {
+            // Page aligned (or more) requests go straight to the page allocator
+            let pages = layout.size().div_ceil(PAGE_SIZE).max(1);
+            let align_order = layout.align().trailing_zeros() as usize;
+            oom::retry(pages * PAGE_SIZE, || crate::page::zero_alloc_internal(pages, align_order))
+        }
+        else {
+            // A null here ends up in the alloc_error_handler
+            let size = align_val(layout.size(), 3);
+            try_kmalloc_untracked(size, layout.align()).map_or(null_mut(), |p| {
+                unsafe { p.write_bytes(0, size) };
+                p
+            })
+        };
+        #[cfg(feature = "alloc_track")]
+        crate::track::add(ret, layout.size(), global_kind(&layout), core::panic::Location::caller());
+        ret
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc_track")]
+        crate::track::remove(ptr, global_kind(&layout));
+        // Same test as alloc, so we know which allocator the pointer came from
+        if layout.align() >= PAGE_SIZE {
+            crate::page::dealloc(ptr);
+        }
+        else {
+            kfree(ptr);
+        }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        kfree(ptr);
+    /// Resize in place when the block (or its free neighbour) has room, so growing a Vec
+    /// doesn't need the old and the new buffer at the same time. Copy only when it must move.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let in_place = if layout.align() >= PAGE_SIZE {
+            // A run of pages can stay as long as it keeps its page count
+            layout.size().div_ceil(PAGE_SIZE) == new_size.div_ceil(PAGE_SIZE)
+        }
+        else {
+            resize_in_place(ptr, new_size)
+        };
+        if in_place {
+            #[cfg(feature = "alloc_track")]
+            crate::track::resize(ptr, global_kind(&layout), new_size);
+            return ptr;
+        }
+
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let new = unsafe { self.alloc(new_layout) };
+        if !new.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new
     }
 }
- 
+
+#[cfg(all(not(test), feature = "alloc_track"))]
+fn global_kind(layout: &Layout) -> crate::track::Kind {
+    crate::track::Kind::Global { align: layout.align() }
+}
+
+#[cfg(not(test))]
 #[global_allocator]
 static GA: OsGlobalAlloc = OsGlobalAlloc {};
 
+/// core::alloc::Allocator over kmalloc/kfree, for `Box::new_in(x, KmemAllocator)` and
+/// friends. Like the global allocator, page aligned (or more) layouts go to PageAllocator.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct KmemAllocator;
+
+impl KmemAllocator {
+    #[cfg_attr(feature = "alloc_track", track_caller)]
+    fn alloc_impl(layout: Layout, zeroed: bool) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.align() >= PAGE_SIZE {
+            return if zeroed {
+                PageAllocator.allocate_zeroed(layout)
+            }
+            else {
+                PageAllocator.allocate(layout)
+            };
+        }
+        if layout.size() == 0 {
+            let dangling = NonNull::new(layout.align() as *mut u8).ok_or(AllocError)?;
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+        let ptr = if zeroed {
+            try_kzmalloc_aligned(layout.size(), layout.align())?
+        }
+        else {
+            try_kmalloc_aligned(layout.size(), layout.align())?
+        };
+        let ptr = NonNull::new(ptr).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+}
+
+unsafe impl Allocator for KmemAllocator {
+    #[cfg_attr(feature = "alloc_track", track_caller)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Self::alloc_impl(layout, false)
+    }
+
+    #[cfg_attr(feature = "alloc_track", track_caller)]
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Self::alloc_impl(layout, true)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.align() >= PAGE_SIZE {
+            unsafe { PageAllocator.deallocate(ptr, layout) }
+        }
+        else if layout.size() != 0 {
+            kfree(ptr.as_ptr());
+        }
+    }
+}
+
+/// Out of memory for good: say where the memory went and panic. lib.rs registers
+/// this as the alloc_error_handler.
 pub fn alloc_error(l: Layout) -> ! {
+    oom::report();
     panic!(
         "Allocator failed to allocate {} bytes with {}-byte alignment.",
         l.size(),
         l.align()
     );
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Rng;
+
+    // A kmem heap growing out of a page heap of roughly the given number of pages
+    fn heap(pages: usize) -> KmemHeap<PageHeap> {
+        let region = vec![0u8; (pages + 2) * PAGE_SIZE].leak();
+        KmemHeap::new(PageHeap::from_slice(region))
+    }
+
+    // Walk the block chain of every chunk and return (free bytes, largest free block,
+    // bytes available for blocks), checking that the sizes add up to exactly each chunk,
+    // that every footer matches its header, that no two free blocks sit side by side and
+    // that the free list holds exactly the free blocks
+    fn walk<P: PageSource>(h: &KmemHeap<P>) -> (usize, usize, usize) {
+        assert_eq!(h.check_heap(), Ok(()));
+        let mut free = 0;
+        let mut free_blocks = 0;
+        let mut largest = 0;
+        let mut capacity = 0;
+        h.for_each_chunk(|start, size| unsafe {
+            let chunk = start as *mut Chunk;
+            let mut head = (*chunk).first();
+            let mut total = CHUNK_OVERHEAD;
+            let mut prev_free = false;
+            while total < size {
+                let block = (*head).get_size();
+                assert!(block >= MIN_BLOCK, "undersized block at {:p}", head);
+                assert_eq!((*head).flags_size, (*footer(head)).flags_size);
+                if (*head).is_free() {
+                    assert!(!prev_free, "free neighbours left unmerged at {:p}", head);
+                    free += block;
+                    free_blocks += 1;
+                    largest = largest.max(block);
+                }
+                prev_free = (*head).is_free();
+                total += block;
+                head = (head as *mut u8).add(block) as *mut AllocList;
+            }
+            assert_eq!(total, size);
+            assert_eq!(head, (*chunk).tail());
+            capacity += size - CHUNK_OVERHEAD;
+        });
+
+        let mut listed = 0;
+        let mut listed_bytes = 0;
+        let mut b = h.free;
+        while !b.is_null() {
+            unsafe {
+                assert!((*b).is_free());
+                listed += 1;
+                listed_bytes += (*b).get_size();
+                b = (*links(b)).next;
+            }
+        }
+        assert_eq!((listed, listed_bytes), (free_blocks, free));
+        (free, largest, capacity)
+    }
+
+    fn chunks<P: PageSource>(h: &KmemHeap<P>) -> usize {
+        let mut n = 0;
+        h.for_each_chunk(|_, _| n += 1);
+        n
+    }
+
+    #[test]
+    fn kmalloc_and_kfree() {
+        let mut h = heap(128);
+        let a = h.kmalloc(10);
+        let b = h.kzmalloc(100);
+        assert!(!a.is_null() && !b.is_null());
+        assert_eq!(a as usize % 8, 0);
+        assert!((b as usize) >= a as usize + 16);
+        assert!((0..100).all(|i| unsafe { *b.add(i) } == 0));
+        h.kfree(a);
+        h.kfree(b);
+        let (free, largest, capacity) = walk(&h);
+        assert_eq!((free, largest), (capacity, capacity));
+    }
+
+    #[test]
+    fn exhaustion_returns_null() {
+        let mut h = heap(100);
+        let mut count = 0;
+        while !h.kmalloc(56).is_null() {
+            count += 1;
+        }
+        assert!(count > 0);
+        // Both the chunks and the page heap behind them are used up
+        assert_eq!(h.pages.stats().free, 0);
+        assert!(walk(&h).1 < 56 + TAGS);
+        assert!(h.kmalloc(56).is_null());
+    }
+
+    #[test]
+    fn heap_grows_and_gives_chunks_back() {
+        let mut h = heap(1024);
+        let first = h.get_size();
+        let ptrs: Vec<_> = (0..400).map(|_| h.kmalloc(2000)).collect();
+        assert!(ptrs.iter().all(|p| !p.is_null()));
+        assert!(chunks(&h) > 1);
+        assert!(h.get_size() > first);
+        // A request bigger than a whole grow step gets a chunk of its own
+        let big = h.kmalloc(KMEM_GROW_PAGES * PAGE_SIZE * 2);
+        assert!(!big.is_null());
+        h.kfree(big);
+        for p in ptrs {
+            h.kfree(p);
+        }
+        assert_eq!(chunks(&h), 1);
+        assert_eq!(h.get_size(), KMEM_GROW_PAGES * PAGE_SIZE);
+        assert_eq!(h.pages.stats().used, KMEM_GROW_PAGES);
+    }
+
+    #[test]
+    fn empty_chunks_kept_when_asked() {
+        let mut h = heap(1024);
+        h.set_release_empty(false);
+        let ptrs: Vec<_> = (0..200).map(|_| h.kmalloc(2000)).collect();
+        let grown = chunks(&h);
+        for p in ptrs {
+            h.kfree(p);
+        }
+        assert_eq!(chunks(&h), grown);
+    }
+
+    #[test]
+    fn coalesce_merges_neighbours() {
+        let mut h = heap(128);
+        let ptrs: Vec<_> = (0..8).map(|_| h.kmalloc(64)).collect();
+        // Free every other block, then the rest, the heap must end up in one piece
+        for p in ptrs.iter().step_by(2) {
+            h.kfree(*p);
+        }
+        let (_, largest, capacity) = walk(&h);
+        assert!(largest < capacity);
+        for p in ptrs.iter().skip(1).step_by(2) {
+            h.kfree(*p);
+        }
+        let (free, largest, capacity) = walk(&h);
+        assert_eq!((free, largest), (capacity, capacity));
+        assert!(!h.kmalloc(capacity - TAGS - 3 * RED_ZONE).is_null());
+        assert_eq!(chunks(&h), 1);
+    }
+
+    #[test]
+    fn check_heap_finds_overruns() {
+        let mut h = heap(128);
+        let a = h.kmalloc(24);
+        let b = h.kmalloc(24);
+        assert_eq!(h.check_heap(), Ok(()));
+        // One byte too far lands on the footer, or on the guard in a debug heap
+        unsafe { *a.add(24) = 0x42 };
+        let err = h.check_heap().unwrap_err();
+        assert_eq!(err.addr, a as usize - LEAD);
+        unsafe { *a.add(24) = *b.add(24) };
+        assert_eq!(h.check_heap(), Ok(()));
+    }
+
+    #[cfg(feature = "debug_heap")]
+    #[test]
+    fn check_heap_finds_use_after_free() {
+        let mut h = heap(128);
+        let a = h.kmalloc(256);
+        let b = h.kmalloc(256);
+        h.kfree(a);
+        assert_eq!(h.check_heap(), Ok(()));
+        unsafe { *a.add(100) = 0 };
+        let err = h.check_heap().unwrap_err();
+        assert_eq!(err.what, "free block written to (use after free)");
+        h.kfree(b);
+    }
+
+    #[cfg(feature = "debug_heap")]
+    #[test]
+    #[should_panic(expected = "back guard overwritten")]
+    fn kfree_checks_guards() {
+        let mut h = heap(128);
+        let a = h.kmalloc(13);
+        unsafe { *a.add(13) = 0 };
+        h.kfree(a);
+    }
+
+    #[cfg(any(feature = "debug_heap", debug_assertions))]
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_kfree_panics() {
+        let mut h = heap(128);
+        let a = h.kmalloc(64);
+        let _b = h.kmalloc(64);
+        h.kfree(a);
+        h.kfree(a);
+    }
+
+    #[cfg(any(feature = "debug_heap", debug_assertions))]
+    #[test]
+    #[should_panic(expected = "kfree: ")]
+    fn kfree_of_a_merged_block_panics() {
+        let mut h = heap(128);
+        let a = h.kmalloc(64);
+        let b = h.kmalloc(64);
+        let _c = h.kmalloc(64);
+        h.kfree(a);
+        // b merges into a's free block, its old header is left behind in the middle
+        h.kfree(b);
+        h.kfree(b);
+    }
+
+    #[test]
+    fn resize_in_place_grows_and_shrinks() {
+        let mut h = heap(128);
+        let a = h.kmalloc(64);
+        let b = h.kmalloc(64);
+        unsafe { a.write_bytes(0x5a, 64) };
+        // b sits right behind a, so a can't grow until b is gone
+        assert!(!h.resize_in_place(a, 200));
+        h.kfree(b);
+        assert!(h.resize_in_place(a, 1000));
+        assert!((0..64).all(|i| unsafe { *a.add(i) } == 0x5a));
+        let c = h.kmalloc(64);
+        assert!(c as usize >= a as usize + 1000);
+        walk(&h);
+
+        // Shrinking gives the tail back as a free block, even with c behind it
+        let (free, _, _) = walk(&h);
+        assert!(h.resize_in_place(a, 16));
+        assert!(walk(&h).0 >= free + 900);
+        assert!(h.resize_in_place(a, 8));
+        h.kfree(a);
+        h.kfree(c);
+        let (free, largest, capacity) = walk(&h);
+        assert_eq!((free, largest), (capacity, capacity));
+    }
+
+    #[test]
+    fn aligned_kmalloc() {
+        let mut h = heap(128);
+        let small = h.kmalloc(8);
+        for align in [16, 64, 256, 4096] {
+            let p = h.kmalloc_aligned(24, align);
+            assert!(!p.is_null());
+            assert_eq!(p as usize % align, 0);
+            // The gap in front of p must have become a free block, not lost space
+            walk(&h);
+            h.kfree(p);
+        }
+        h.kfree(small);
+        let (free, largest, capacity) = walk(&h);
+        assert_eq!((free, largest), (capacity, capacity));
+    }
+
+    #[test]
+    fn random_kmalloc_kfree_sequences() {
+        for seed in 1..20u64 {
+            let mut h = heap(512);
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let mut live: Vec<(*mut u8, usize, u8)> = Vec::new();
+            for step in 0..3000usize {
+                if live.is_empty() || !rng.next().is_multiple_of(3) {
+                    let size = 1 + rng.next() % 700;
+                    let p = h.kmalloc(size);
+                    if !p.is_null() {
+                        let tag = step as u8;
+                        unsafe { p.write_bytes(tag, size) };
+                        live.push((p, size, tag));
+                    }
+                }
+                else {
+                    let (p, size, tag) = live.swap_remove(rng.next() % live.len());
+                    assert!((0..size).all(|i| unsafe { *p.add(i) } == tag));
+                    h.kfree(p);
+                }
+                walk(&h);
+            }
+            for (p, size, tag) in live {
+                assert!((0..size).all(|i| unsafe { *p.add(i) } == tag));
+                h.kfree(p);
+            }
+            let (free, largest, capacity) = walk(&h);
+            assert_eq!((free, largest), (capacity, capacity));
+            assert_eq!(chunks(&h), 1);
+        }
+    }
+}
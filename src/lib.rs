@@ -1,19 +1,34 @@
-#![no_std]  // No standard library
-#![feature(asm_experimental_arch , allocator_api , alloc_error_handler)]
+#![cfg_attr(not(test), no_std)]  // No standard library (the host tests under cargo test do get one)
+#![feature(allocator_api)]
+#![cfg_attr(not(test) , feature(alloc_error_handler))]
+#[cfg(all(not(test) , target_arch = "riscv64"))]
 use core::arch::asm;
 //use core::option::Option;
 pub mod uart;  // This is like #include in C++
 //use core::panic;
 
 extern crate alloc ;
+#[cfg(not(test))]
 use alloc::alloc::* ;
+use page::{PhysAddr , PteFlags , VirtAddr} ;
 
+#[cfg(not(test))]
 #[macro_export]
 macro_rules! print
 {
     ($($args:tt)+) => ({
         use core::fmt::Write ;
-        let _ = write!(crate::uart::Uart::new(0x1000_0000) , $($args)+) ;
+        let _ = write!($crate::uart::Uart::new(0x1000_0000) , $($args)+) ;
+    });
+}
+
+// There is no UART when the allocators run as host tests, print to stdout instead
+#[cfg(test)]
+#[macro_export]
+macro_rules! print
+{
+    ($($args:tt)+) => ({
+        std::print!($($args)+) ;
     });
 }
 
@@ -33,6 +48,7 @@ macro_rules! println
     }) ;
 }
 
+#[cfg(not(test))]
 #[unsafe(no_mangle)] // no mangling
 // Exception handling personality --> this function is the interface that Rust runtime uses to interact with the exception handling
 // We manually override this so that it does nothing
@@ -40,6 +56,7 @@ extern "C" fn eh_personality() {
 
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> !{
     print!("Aborting: ") ;
@@ -57,17 +74,30 @@ fn panic(info: &core::panic::PanicInfo) -> !{
     abort() ;
 }
 
+// Out of memory in the global allocator, after the shrinkers had their go (see oom.rs)
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> !{
+    kmem::alloc_error(layout) ;
+}
+
+#[cfg(not(test))]
 #[unsafe(no_mangle)]   
 extern "C"
 fn abort() -> !{
     loop{
+        #[cfg(target_arch = "riscv64")]
         unsafe{
             asm!("wfi" , options(nomem, nostack, preserves_flags)) ;
         }
+        // Nothing to wait for when the library is built for the host
+        #[cfg(not(target_arch = "riscv64"))]
+        core::hint::spin_loop() ;
     }
 }
 
 
+#[cfg(not(test))]
 unsafe extern "C" {
         static TEXT_START: usize;
         static TEXT_END: usize;
@@ -80,141 +110,151 @@ unsafe extern "C" {
         static KERNEL_STACK_START: usize;
         static KERNEL_STACK_END: usize;
         static HEAP_START: usize;
-        static HEAP_SIZE: usize;
         static mut KERNEL_TABLE: usize;
 }
 
-pub fn id_map_range(root: &mut page::Table , start:usize , end:usize , bits:i64){
-    let mut memaddr = start & !(page::PAGE_SIZE -1) ;
-    let num_pages = (page::align_val(end , 12) - memaddr) / page::PAGE_SIZE ;
-    
-    for _ in 0..num_pages{
-        page::mapping(root , memaddr , memaddr , bits , 0) ;
-        memaddr += 1 << 12 ;
+// Identity map [start , end) with the biggest leaves that fit: 1 GiB and 2 MiB (and up in Sv48 / Sv57)
+// where the address is aligned and the rest of the range is long enough, 4 KiB pages at the edges
+pub fn id_map_range(root: &mut page::Table , start: PhysAddr , end: PhysAddr , flags: PteFlags){
+    let mut memaddr = start.align_down(page::PAGE_SIZE) ;
+    let end = end.align_up(page::PAGE_SIZE) ;
+
+    while memaddr < end{
+        // va == pa, so one alignment test does for both
+        let level = (0..page::paging_mode().levels()).rev()
+            .find(|&l| memaddr.is_aligned(page::level_size(l)) && memaddr.as_usize() + page::level_size(l) <= end.as_usize())
+            .unwrap_or(0) ;
+        page::mapping(root , VirtAddr::new(memaddr.as_usize()) , memaddr , flags , level) ;
+        memaddr += page::level_size(level) ;
     }
 }
 
+#[cfg(not(test))]
 #[unsafe(no_mangle)] 
-extern "C" fn kinit() -> usize{
+extern "C" fn kinit(dtb: usize) -> usize{
     // Interrupts should be disabled 
+    // dtb is the device tree pointer _start saved from a1 (0 if firmware gave us none)
     uart::Uart::new(0x1000_0000).init() ;
-    page::init() ;
+    // Pick the paging mode before anything gets mapped, all tables are built for it
+    page::set_paging_mode(page::probe_paging_mode()) ;
+    page::init(dtb) ;
     kmem::init() ;
     
     let root_ptr = kmem::get_page_table();
-    let root_u = root_ptr as usize;
-    let mut root = unsafe { root_ptr.as_mut().unwrap() };
-    let kheap_head = kmem::get_head() as usize;
-    let total_pages = kmem::get_num_allocations();
-
-    id_map_range(&mut root, kheap_head, kheap_head + total_pages * 4096, page::EntryBits::ReadWrite.val(),);
+    let root_pa = PhysAddr::from_ptr(root_ptr);
+    let root = unsafe { root_ptr.as_mut().unwrap() };
     unsafe {
         // Map heap descriptors
-        let num_pages = HEAP_SIZE / page::PAGE_SIZE;
-        id_map_range(&mut root,
-                     HEAP_START,
-                     HEAP_START + num_pages,
-                     page::EntryBits::ReadWrite.val()
+        id_map_range(root,
+                     PhysAddr::new(HEAP_START),
+                     PhysAddr::new(page::get_alloc_start()),
+                     PteFlags::RW
+        );
+        // Map the whole page heap. Everything allocated from it (kmem chunks , slabs , page
+        // tables) is written to through its physical address, whether it exists yet or not.
+        id_map_range(root,
+                     PhysAddr::new(page::get_alloc_start()),
+                     PhysAddr::new(page::get_alloc_end()),
+                     PteFlags::RW
         );
         // Map executable section
         id_map_range(
-            &mut root,
-            TEXT_START,
-            TEXT_END,
-            page::EntryBits::ReadExecute.val(),
+            root,
+            PhysAddr::new(TEXT_START),
+            PhysAddr::new(TEXT_END),
+            PteFlags::RX,
         );
         // Map rodata section
         // We put the ROdata section into the text section, so they can
         // potentially overlap however, we only care that it's read
         // only.
         id_map_range(
-            &mut root,
-            RODATA_START,
-            RODATA_END,
-            page::EntryBits::ReadExecute.val(),
+            root,
+            PhysAddr::new(RODATA_START),
+            PhysAddr::new(RODATA_END),
+            PteFlags::RX,
         );
         // Map data section
         id_map_range(
-            &mut root,
-            DATA_START,
-            DATA_END,
-            page::EntryBits::ReadWrite.val(),
+            root,
+            PhysAddr::new(DATA_START),
+            PhysAddr::new(DATA_END),
+            PteFlags::RW,
         );
         // Map bss section
         id_map_range(
-            &mut root,
-            BSS_START,
-            BSS_END,
-            page::EntryBits::ReadWrite.val(),
+            root,
+            PhysAddr::new(BSS_START),
+            PhysAddr::new(BSS_END),
+            PteFlags::RW,
         );
         // Map kernel stack
         id_map_range(
-            &mut root,
-            KERNEL_STACK_START,
-            KERNEL_STACK_END,
-            page::EntryBits::ReadWrite.val(),
+            root,
+            PhysAddr::new(KERNEL_STACK_START),
+            PhysAddr::new(KERNEL_STACK_END),
+            PteFlags::RW,
         );
     }
 
     // UART
     page::mapping(
-        &mut root,
-        0x1000_0000,
-        0x1000_0000,
-        page::EntryBits::ReadWrite.val(),
+        root,
+        VirtAddr::new(0x1000_0000),
+        PhysAddr::new(0x1000_0000),
+        PteFlags::RW,
         0
     );
 
     // CLINT
     //  -> MSIP
     page::mapping(
-        &mut root,
-        0x0200_0000,
-        0x0200_0000,
-        page::EntryBits::ReadWrite.val(),
+        root,
+        VirtAddr::new(0x0200_0000),
+        PhysAddr::new(0x0200_0000),
+        PteFlags::RW,
         0
     );
     //  -> MTIMECMP
     page::mapping(
-        &mut root,
-        0x0200_b000,
-        0x0200_b000,
-        page::EntryBits::ReadWrite.val(),
+        root,
+        VirtAddr::new(0x0200_b000),
+        PhysAddr::new(0x0200_b000),
+        PteFlags::RW,
         0
     );
     //  -> MTIME
     page::mapping(
-        &mut root,
-        0x0200_c000,
-        0x0200_c000,
-        page::EntryBits::ReadWrite.val(),
+        root,
+        VirtAddr::new(0x0200_c000),
+        PhysAddr::new(0x0200_c000),
+        PteFlags::RW,
         0
     );
     // PLIC
     id_map_range(
-        &mut root,
-        0x0c00_0000,
-        0x0c00_2000,
-        page::EntryBits::ReadWrite.val(),
+        root,
+        PhysAddr::new(0x0c00_0000),
+        PhysAddr::new(0x0c00_2000),
+        PteFlags::RW,
     );
     id_map_range(
-        &mut root,
-        0x0c20_0000,
-        0x0c20_8000,
-        page::EntryBits::ReadWrite.val(),
+        root,
+        PhysAddr::new(0x0c20_0000),
+        PhysAddr::new(0x0c20_8000),
+        PteFlags::RW,
     );	
-    
-    let p = 0x8005_7000 as usize;
-    let m = page::translate(&root, p).unwrap_or(0);
 
     unsafe {
-        KERNEL_TABLE = root_u;
+        KERNEL_TABLE = root_pa.as_usize();
     }
-    // table / 4096    Sv39 mode
-    (root_u >> 12)  | (8 << 60)
+    // table / 4096 with the mode we probed (Sv39 , Sv48 or Sv57)
+    page::paging_mode().satp(root_pa)
 }
 
+#[cfg(not(test))]
 #[unsafe(no_mangle)]
+#[allow(clippy::collapsible_if)]  // One if per byte of an escape sequence reads better here
 extern "C"
 fn kmain(){
     let mut uart1 = uart::Uart::new(0x1000_0000) ;
@@ -266,5 +306,14 @@ fn kmain(){
         }
     }	
 }
+pub mod fdt ;
 pub mod kmem ;
+pub mod lock ;
+pub mod oom ;
 pub mod page ;
+pub mod slab ;
+pub mod vm ;
+#[cfg(feature = "alloc_track")]
+pub mod track ;
+#[cfg(test)]
+mod test_util ;
@@ -0,0 +1,161 @@
+// Spinlock for kernel state shared between harts and trap handlers. Taking the lock
+// also clears sstatus.SIE, so a trap handler on the same hart can't come in and spin
+// forever on a lock its own hart holds. The previous SIE is put back when the guard drops.
+// Use a Mutex<T> static instead of a static mut:
+//     static THINGS: Mutex<Things> = Mutex::new(Things::new());
+//     THINGS.lock().add(x);
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A spinlock around a T
+pub struct Mutex<T> {
+    data: UnsafeCell<T>,
+    locked: AtomicBool,
+}
+
+// Anyone can lock it from anywhere, the lock hands out one &mut at a time
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+/// The lock is held as long as this lives
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+    sie: bool, // sstatus.SIE before we took the lock
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            data: UnsafeCell::new(data),
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    /// Spin until we have the lock, with supervisor interrupts off
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            // Wait for it to look free before trying again, with interrupts back on meanwhile
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// The lock if nobody holds it, without spinning
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let sie = intr_off();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(MutexGuard { lock: self, sie })
+        }
+        else {
+            intr_restore(sie);
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// No locking needed when we have the only reference
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        intr_restore(self.sie);
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+const SSTATUS_SIE: usize = 1 << 1;
+
+// Clear sstatus.SIE and return whether it was set
+#[cfg(target_arch = "riscv64")]
+fn intr_off() -> bool {
+    let old: usize;
+    unsafe {
+        core::arch::asm!("csrrc {0}, sstatus, {1}", out(reg) old, in(reg) SSTATUS_SIE);
+    }
+    old & SSTATUS_SIE != 0
+}
+
+#[cfg(target_arch = "riscv64")]
+fn intr_restore(sie: bool) {
+    if sie {
+        unsafe {
+            core::arch::asm!("csrs sstatus, {0}", in(reg) SSTATUS_SIE);
+        }
+    }
+}
+
+// There are no supervisor interrupts to mask when the allocators run as host tests
+#[cfg(not(target_arch = "riscv64"))]
+fn intr_off() -> bool {
+    false
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+fn intr_restore(_sie: bool) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn lock_excludes_other_threads() {
+        let m = Arc::new(Mutex::new(0usize));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let m = m.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        // Split read and write so a missing lock would lose updates
+                        let mut g = m.lock();
+                        let v = *g;
+                        *g = v + 1;
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*m.lock(), 40_000);
+    }
+
+    #[test]
+    fn try_lock_fails_while_held() {
+        let m = Mutex::new(1);
+        let g = m.try_lock().unwrap();
+        assert!(m.is_locked() && m.try_lock().is_none());
+        drop(g);
+        assert!(!m.is_locked() && m.try_lock().is_some());
+    }
+}
@@ -0,0 +1,207 @@
+// What we do when memory runs out. The try_ allocation functions in kmem.rs and page.rs
+// don't give up on the first failure: they ask the registered shrinkers (caches and the
+// like holding memory they can do without) to hand some back and try again. Only when
+// nobody can free anything do they return an AllocFailure, and the global allocator
+// ends up in the alloc_error_handler in lib.rs.
+use core::fmt;
+use crate::lock::Mutex;
+
+/// Why an allocation failed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AllocFailure {
+    /// Nothing left, even after shrinking
+    OutOfMemory { size: usize, align: usize },
+    /// The alignment isn't a power of two, or the size overflows
+    BadLayout,
+}
+
+impl fmt::Display for AllocFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocFailure::OutOfMemory { size, align } => {
+                write!(f, "out of memory ({} bytes, {}-byte aligned)", size, align)
+            }
+            AllocFailure::BadLayout => write!(f, "bad layout"),
+        }
+    }
+}
+
+impl From<AllocFailure> for core::alloc::AllocError {
+    fn from(_: AllocFailure) -> Self {
+        core::alloc::AllocError
+    }
+}
+
+/// Asked to free about the given number of bytes, returns how many it actually freed
+pub type Shrinker = fn(usize) -> usize;
+
+pub const MAX_SHRINKERS: usize = 8;
+
+/// Rounds of shrinking and retrying before an allocation fails
+pub const SHRINK_PASSES: usize = 3;
+
+/// The shrinkers the try_ functions fall back on when an allocation fails
+#[derive(Copy, Clone)]
+pub struct Registry {
+    shrinkers: [Option<(&'static str, Shrinker)>; MAX_SHRINKERS],
+}
+
+impl Registry {
+    pub const fn new() -> Self {
+        Registry {
+            shrinkers: [None; MAX_SHRINKERS],
+        }
+    }
+
+    /// Add a shrinker, returns false if the registry is full
+    pub fn register(&mut self, name: &'static str, f: Shrinker) -> bool {
+        match self.shrinkers.iter_mut().find(|s| s.is_none()) {
+            Some(slot) => {
+                *slot = Some((name, f));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn unregister(&mut self, name: &'static str) {
+        for slot in self.shrinkers.iter_mut() {
+            if matches!(slot, Some((n, _)) if *n == name) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Ask the shrinkers, in the order they registered, for bytes bytes. Returns what they freed.
+    pub fn shrink(&self, bytes: usize) -> usize {
+        let mut freed = 0;
+        for (_, f) in self.shrinkers.iter().flatten() {
+            if freed >= bytes {
+                break;
+            }
+            freed += f(bytes - freed);
+        }
+        freed
+    }
+
+    /// Run alloc, and while it comes back null, shrink by bytes and run it again, as long
+    /// as the shrinkers manage to free something
+    pub fn retry<F: FnMut() -> *mut u8>(&self, bytes: usize, mut alloc: F) -> *mut u8 {
+        let mut ret = alloc();
+        let mut pass = 0;
+        while ret.is_null() && pass < SHRINK_PASSES && self.shrink(bytes) > 0 {
+            ret = alloc();
+            pass += 1;
+        }
+        ret
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+pub fn register_shrinker(name: &'static str, f: Shrinker) -> bool {
+    REGISTRY.lock().register(name, f)
+}
+
+pub fn unregister_shrinker(name: &'static str) {
+    REGISTRY.lock().unregister(name)
+}
+
+// Shrinking and retrying run on a copy of the registry, so the shrinkers and the
+// allocation itself are free to take whatever locks they need
+
+pub fn shrink(bytes: usize) -> usize {
+    let registry = *REGISTRY.lock();
+    registry.shrink(bytes)
+}
+
+pub fn retry<F: FnMut() -> *mut u8>(bytes: usize, alloc: F) -> *mut u8 {
+    let registry = *REGISTRY.lock();
+    registry.retry(bytes, alloc)
+}
+
+/// Print where the memory went, for the alloc_error_handler
+pub fn report() {
+    // Totals only, a list of every allocation is too long to read when we are about to halt
+    let pages = crate::page::stats();
+    println!(
+        "page: {} of {} pages used, {} reserved, {} free, largest free run {}",
+        pages.used, pages.total, pages.reserved, pages.free, pages.largest_free_run
+    );
+    println!(
+        "kmem: {} pages in the heap",
+        crate::kmem::get_num_allocations()
+    );
+    crate::slab::for_each_class(|c| {
+        if c.slabs > 0 {
+            println!(
+                "{:<14} {:>6}/{:<6} objects in {} slabs",
+                c.name, c.inuse, c.capacity, c.slabs
+            );
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::ptr::null_mut;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static POOL: AtomicUsize = AtomicUsize::new(0);
+
+    // Hands back up to 100 bytes per call while the pool lasts
+    fn pool_shrinker(bytes: usize) -> usize {
+        let freed = bytes.min(100).min(POOL.load(Ordering::SeqCst));
+        POOL.fetch_sub(freed, Ordering::SeqCst);
+        freed
+    }
+
+    fn nothing_shrinker(_: usize) -> usize {
+        0
+    }
+
+    #[test]
+    fn retry_shrinks_until_the_allocation_fits() {
+        let mut r = Registry::new();
+        assert!(r.register("nothing", nothing_shrinker));
+        assert!(r.register("pool", pool_shrinker));
+        POOL.store(250, Ordering::SeqCst);
+
+        // Succeeds once 200 bytes have been shrunk out of the pool
+        let mut calls = 0;
+        let ret = r.retry(100, || {
+            calls += 1;
+            if POOL.load(Ordering::SeqCst) <= 50 { 0x1000 as *mut u8 } else { null_mut() }
+        });
+        assert_eq!(ret as usize, 0x1000);
+        assert_eq!(calls, 3);
+
+        // Gives up after SHRINK_PASSES rounds or once nothing can be freed
+        let mut calls = 0;
+        let ret = r.retry(100, || {
+            calls += 1;
+            null_mut()
+        });
+        assert!(ret.is_null());
+        assert_eq!(calls, 2);
+
+        r.unregister("pool");
+        assert_eq!(r.shrink(100), 0);
+    }
+
+    #[test]
+    fn registry_fills_up() {
+        let mut r = Registry::new();
+        for _ in 0..MAX_SHRINKERS {
+            assert!(r.register("nothing", nothing_shrinker));
+        }
+        assert!(!r.register("one too many", nothing_shrinker));
+    }
+}
@@ -1,14 +1,27 @@
-use core::{mem::size_of , ptr::null_mut} ;
+use core::alloc::{AllocError , Allocator , Layout} ;
+use core::{mem::size_of , ptr::{null_mut , NonNull}} ;
+use core::fmt ;
+use core::ops::{Add , AddAssign , BitAnd , BitAndAssign , BitOr , BitOrAssign , Not , Range , Sub} ;
+use core::sync::atomic::{AtomicUsize , Ordering} ;
+use crate::fdt::Fdt ;
+use crate::lock::{Mutex , MutexGuard} ;
+use crate::oom::{self , AllocFailure} ;
 
+#[cfg(not(test))]
 unsafe extern "C"{
     static HEAP_START: usize ;
     static HEAP_SIZE: usize ;
 }
 
-static mut ALLOC_START: usize = 0 ;
-const PAGE_ORDER: usize = 12 ;
+pub const PAGE_ORDER: usize = 12 ;
 pub const PAGE_SIZE: usize = 1 << 12 ;
 
+// Largest buddy block is 2^MAX_ORDER pages (2^20 * 4 KiB = 4 GiB)
+pub const MAX_ORDER: usize = 20 ;
+
+// Number of physical ranges (start , end) a heap can keep out of the allocator
+const MAX_RESERVED: usize = 32 ;
+
 // Align it up to "order" bits
 pub const fn align_val(val: usize , order: usize) -> usize{
     let o = (1usize << order) - 1 ;
@@ -16,11 +29,12 @@ pub const fn align_val(val: usize , order: usize) -> usize{
 }
 
 #[repr(u8)]
-
 pub enum PageBits{
     Empty = 0 ,
     Taken = 1 << 0 ,
     Last = 1 << 1 ,
+    Free = 1 << 2 ,  // First page of a free buddy block, the block order is in Page::order
+    Reserved = 1 << 3 ,  // Never handed out by alloc (firmware , DTB , ...)
 }
 
 impl PageBits{
@@ -31,6 +45,8 @@ impl PageBits{
 
 pub struct Page{
     flags: u8 ,
+    order: u8 ,
+    refs: u16 ,  // Only meaningful on the first page of a taken run
 }
 
 impl Page{
@@ -45,80 +61,668 @@ impl Page{
         (self.flags & PageBits::Taken.val()) != 0
     }
 
+    // Function that checks if this page starts a free buddy block
+    pub fn is_free_head(&self) -> bool{
+        (self.flags & PageBits::Free.val()) != 0
+    }
+
+    // Function that checks if the page is reserved and must never be allocated
+    pub fn is_reserved(&self) -> bool{
+        (self.flags & PageBits::Reserved.val()) != 0
+    }
+
+    pub fn get_order(&self) -> usize{
+        self.order as usize
+    }
+
     pub fn clear(&mut self){
         self.flags = PageBits::Empty.val() ;
+        self.order = 0 ;
+        self.refs = 0 ;
     }
 
     pub fn set_flag(&mut self , flag: PageBits){
-        self.flags = flag.val() | self.flags ;
+        self.flags |= flag.val() ;
     }
 
     pub fn clear_flag(&mut self , flag: PageBits){
-        self.flags = self.flags & !flag.val() ;
+        self.flags &= !flag.val() ;
     }
 
 }
 
-pub fn init(){
-    unsafe{
-        let num_pages = HEAP_SIZE / PAGE_SIZE ;
-        let ptr = HEAP_START as *mut Page ; // Pointer to the first page
+// A free block stores the links of its free list in its first bytes
+struct FreeBlock{
+    next: usize ,
+    prev: usize ,
+}
 
-        // Clear all pages
-        let mut i = 0 ;
-        while i < num_pages{
-            (*ptr.add(i)).clear() ;
-            i += 1 ;
+// Smallest order whose block holds at least "pages" pages
+const fn order_for(pages: usize) -> usize{
+    let mut order = 0 ;
+    while (1usize << order) < pages{
+        order += 1 ;
+    }
+    order
+}
+
+// Why an address isn't the first page of a live run
+#[derive(Copy , Clone , Debug)]
+enum BadRun{
+    Null ,
+    Outside ,     // Not between alloc_start and alloc_end
+    Misaligned ,
+    Interior ,    // Inside a run, past its first page
+    Free ,        // Nothing allocated there (double free ?)
+}
+
+// Snapshot of the page allocator, all counts are in pages
+#[derive(Copy , Clone , Debug)]
+pub struct PageStats{
+    pub total: usize ,
+    pub used: usize ,
+    pub reserved: usize ,
+    pub free: usize ,
+    pub largest_free_run: usize ,
+}
+
+// A buddy page allocator over one contiguous region of memory.
+// The Page descriptors live at the front of the region and the pages follow, aligned to PAGE_SIZE.
+// init sets up the kernel's over HEAP_START, from_slice makes one over any buffer.
+pub struct PageHeap{
+    desc: usize ,  // Address of the first Page descriptor
+    alloc_start: usize ,
+    alloc_end: usize ,
+    // Head of the free list for every order. 0 means the list is empty.
+    // The lists are doubly linked through the free blocks themselves (see FreeBlock).
+    free_lists: [usize ; MAX_ORDER + 1] ,
+    // Physical ranges (start , end) kept out of the allocator
+    reserved: [(usize , usize) ; MAX_RESERVED] ,
+    num_reserved: usize ,
+}
+
+impl PageHeap{
+    // A heap that owns nothing yet, reserve() calls are remembered until init_region
+    pub const fn empty() -> Self{
+        PageHeap{
+            desc: 0 ,
+            alloc_start: 0 ,
+            alloc_end: 0 ,
+            free_lists: [0 ; MAX_ORDER + 1] ,
+            reserved: [(0 , 0) ; MAX_RESERVED] ,
+            num_reserved: 0 ,
+        }
+    }
+
+    // Build a heap over a byte buffer
+    pub fn from_slice(region: &'static mut [u8]) -> Self{
+        let mut heap = Self::empty() ;
+        let start = region.as_mut_ptr() as usize ;
+        unsafe{
+            heap.init_region(start , start + region.len()) ;
+        }
+        heap
+    }
+
+    /// Take over the memory [start , end). Everything in it is clobbered, apart from the
+    /// reserved ranges which are left alone.
+    ///
+    /// # Safety
+    /// [start , end) must be memory we can write, that nothing else uses (outside the reserved
+    /// ranges) for as long as the heap lives.
+    pub unsafe fn init_region(&mut self , start: usize , end: usize){
+        // The descriptors hold u16s, keep them naturally aligned. They go after any reserved
+        // range they would run into (a DTB right at the start, say), clearing them would wipe it.
+        let mut start = align_val(start , 3) ;
+        let mut num_pages = end.saturating_sub(start) / PAGE_SIZE ;
+        let mut r = 0 ;
+        while r < self.num_reserved && num_pages > 0{
+            let (rsv_start , rsv_end) = self.reserved[r] ;
+            if rsv_start < start + num_pages * size_of::<Page, >() && rsv_end > start{
+                start = align_val(rsv_end , 3).min(end) ;
+                num_pages = (end - start) / PAGE_SIZE ;
+                r = 0 ;  // The descriptors moved, check them against every range again
+                continue ;
+            }
+            r += 1 ;
+        }
+        let ptr = start as *mut Page ; // Pointer to the first page
+
+        unsafe{
+            // Clear all pages
+            let mut i = 0 ;
+            while i < num_pages{
+                (*ptr.add(i)).clear() ;
+                i += 1 ;
+            }
         }
 
         // Check from where we can allocate pages. Align it to page boundary
-        ALLOC_START = align_val(HEAP_START + num_pages * size_of::<Page, >() , PAGE_ORDER) ;
+        self.desc = start ;
+        self.alloc_start = align_val(start + num_pages * size_of::<Page, >() , PAGE_ORDER) ;
+        self.alloc_end = (end & !(PAGE_SIZE - 1)).max(self.alloc_start) ;
+        self.free_lists = [0 ; MAX_ORDER + 1] ;
+
+        unsafe{
+            // Mark the reserved pages that fall inside the heap
+            let mut r = 0 ;
+            while r < self.num_reserved{
+                let (first , last) = self.page_span(self.reserved[r].0 , self.reserved[r].1) ;
+                let mut addr = first ;
+                while addr < last{
+                    (*self.descriptor(addr)).set_flag(PageBits::Reserved) ;
+                    addr += PAGE_SIZE ;
+                }
+                r += 1 ;
+            }
+
+            // Hand everything else after the descriptors to the buddy lists
+            let mut run_start = self.alloc_start ;
+            let mut addr = self.alloc_start ;
+            while addr < self.alloc_end{
+                if (*self.descriptor(addr)).is_reserved(){
+                    self.free_range(run_start , (addr - run_start) / PAGE_SIZE) ;
+                    run_start = addr + PAGE_SIZE ;
+                }
+                addr += PAGE_SIZE ;
+            }
+            self.free_range(run_start , (self.alloc_end - run_start) / PAGE_SIZE) ;
+        }
     }
-}
 
-// We want to do a contiguous allocation for the requested number of pages
-pub fn alloc(pages: usize) -> *mut u8{
-    assert!(pages > 0) ;
-    unsafe{
-        let num_pages = HEAP_SIZE / PAGE_SIZE ;
-        let ptr = HEAP_START as *mut Page ;
-        let mut i = 0 ;
-        while i < num_pages{
-            let mut flag = false ;
-            if !(*ptr.add(i)).is_taken() {
-                flag = true ;
-                let mut j = i ;
-                while j < i + pages && j < num_pages{
-                    if (*ptr.add(j)).is_taken(){
-                        flag = false ;
-                        break ;
+    /// init_region over [start , end) with what the DTB at dtb (0 for none) tells us: the heap runs up to
+    /// the end of the /memory range holding start, and the blob plus /reserved-memory are reserved.
+    /// Other /memory ranges aren't used, the heap covers one contiguous region.
+    /// They are all reserved before init_region, which keeps the descriptors off them.
+    ///
+    /// # Safety
+    /// As for init_region, over the range the heap ends up with. dtb is 0 or points to readable memory.
+    pub unsafe fn init_with_fdt(&mut self , start: usize , end: usize , dtb: usize){
+        let mut end = end ;
+        if let Some(fdt) = Fdt::from_addr(dtb){
+            fdt.for_each_memory(|mem , size| {
+                if start >= mem && start < mem + size{
+                    end = mem + size ;
+                }
+            }) ;
+            let (blob_start , blob_end) = fdt.range() ;
+            self.add_reserved(blob_start , blob_end) ;
+            fdt.for_each_reserved(|rsv , size| {
+                self.add_reserved(rsv , rsv + size) ;
+            }) ;
+        }
+        unsafe{
+            self.init_region(start , end) ;
+        }
+    }
+
+    // Address of the first Page descriptor
+    pub fn get_desc_start(&self) -> usize{
+        self.desc
+    }
+
+    // First allocatable address, everything from the descriptors up to here is the descriptor table
+    pub fn get_alloc_start(&self) -> usize{
+        self.alloc_start
+    }
+
+    // End of the allocatable memory (exclusive)
+    pub fn get_alloc_end(&self) -> usize{
+        self.alloc_end
+    }
+
+    fn is_initialized(&self) -> bool{
+        self.alloc_end != 0
+    }
+
+    // Pages of [start , end) that are inside the heap, rounded out to whole pages
+    fn page_span(&self , start: usize , end: usize) -> (usize , usize){
+        let first = (start & !(PAGE_SIZE - 1)).max(self.alloc_start) ;
+        let last = align_val(end , PAGE_ORDER).min(self.alloc_end) ;
+        (first , last.max(first))
+    }
+
+    // Get the descriptor of the page at addr
+    unsafe fn descriptor(&self , addr: usize) -> *mut Page{
+        unsafe{
+            (self.desc as *mut Page).add((addr - self.alloc_start) / PAGE_SIZE)
+        }
+    }
+
+    unsafe fn push_free(&mut self , addr: usize , order: usize){
+        unsafe{
+            let block = addr as *mut FreeBlock ;
+            let head = self.free_lists[order] ;
+            (*block).prev = 0 ;
+            (*block).next = head ;
+            if head != 0{
+                (*(head as *mut FreeBlock)).prev = addr ;
+            }
+            self.free_lists[order] = addr ;
+
+            let page = self.descriptor(addr) ;
+            (*page).set_flag(PageBits::Free) ;
+            (*page).order = order as u8 ;
+        }
+    }
+
+    unsafe fn remove_free(&mut self , addr: usize , order: usize){
+        unsafe{
+            let block = addr as *mut FreeBlock ;
+            let (next , prev) = ((*block).next , (*block).prev) ;
+            if prev != 0{
+                (*(prev as *mut FreeBlock)).next = next ;
+            }
+            else{
+                self.free_lists[order] = next ;
+            }
+            if next != 0{
+                (*(next as *mut FreeBlock)).prev = prev ;
+            }
+            (*self.descriptor(addr)).clear() ;
+        }
+    }
+
+    // Put a naturally aligned block of 2^order pages back, merging it with its buddy as long as we can.
+    // Buddies are computed on the physical page number so a block of order n is always 2^n pages aligned.
+    unsafe fn free_block(&mut self , addr: usize , order: usize){
+        unsafe{
+            let mut addr = addr ;
+            let mut order = order ;
+            while order < MAX_ORDER{
+                let buddy = ((addr >> PAGE_ORDER) ^ (1 << order)) << PAGE_ORDER ;
+                if buddy < self.alloc_start || buddy + (PAGE_SIZE << order) > self.alloc_end{
+                    break ;
+                }
+                // Reserved pages are never free heads, so we never merge across them
+                let page = self.descriptor(buddy) ;
+                if !(*page).is_free_head() || (*page).get_order() != order{
+                    break ;
+                }
+                self.remove_free(buddy , order) ;
+                addr = if buddy < addr { buddy } else { addr } ;
+                order += 1 ;
+            }
+            self.push_free(addr , order) ;
+        }
+    }
+
+    // Return an arbitrary run of pages by splitting it into the largest aligned blocks that fit
+    unsafe fn free_range(&mut self , addr: usize , pages: usize){
+        let mut addr = addr ;
+        let mut pages = pages ;
+        while pages > 0{
+            let pfn = addr >> PAGE_ORDER ;
+            let mut order = 0 ;
+            while order < MAX_ORDER && pfn & (1 << order) == 0 && (2usize << order) <= pages{
+                order += 1 ;
+            }
+            unsafe{
+                self.free_block(addr , order) ;
+            }
+            addr += PAGE_SIZE << order ;
+            pages -= 1 << order ;
+        }
+    }
+
+    // Record a physical range [start , end) that must stay out of the free lists
+    fn add_reserved(&mut self , start: usize , end: usize) -> bool{
+        if self.num_reserved == MAX_RESERVED{
+            println!("page: too many reserved ranges, ignoring {:#x}..{:#x}" , start , end) ;
+            return false ;
+        }
+        self.reserved[self.num_reserved] = (start , end) ;
+        self.num_reserved += 1 ;
+        true
+    }
+
+    // Keep the physical range [start , end) away from the allocator (firmware , initrd , MMIO holes ...).
+    // Partially covered pages are reserved whole. Before init_region the range is remembered and applied there,
+    // afterwards its free pages are pulled out of the buddy lists right away.
+    // Returns false and reserves nothing if part of the range is already allocated or the list of
+    // reserved ranges is full.
+    pub fn reserve(&mut self , start: usize , end: usize) -> bool{
+        if start >= end{
+            return true ;
+        }
+        // Not initialized yet, init_region will take care of it
+        if !self.is_initialized(){
+            return self.add_reserved(start , end) ;
+        }
+
+        let (first , last) = self.page_span(start , end) ;
+        unsafe{
+            // Refuse the whole thing if somebody already owns a page in it
+            let mut addr = first ;
+            while addr < last{
+                if (*self.descriptor(addr)).is_taken(){
+                    return false ;
+                }
+                addr += PAGE_SIZE ;
+            }
+
+            // is_reserved looks at the list for addresses outside the heap, no room means no reservation
+            if !self.add_reserved(start , end){
+                return false ;
+            }
+
+            let mut addr = first ;
+            while addr < last{
+                if !(*self.descriptor(addr)).is_reserved(){
+                    self.carve_page(addr) ;
+                }
+                addr += PAGE_SIZE ;
+            }
+        }
+        true
+    }
+
+    // Take the free page at addr out of the buddy block holding it and mark it reserved.
+    // The rest of the block goes back to the free lists.
+    unsafe fn carve_page(&mut self , addr: usize){
+        unsafe{
+            let mut order = 0 ;
+            while order <= MAX_ORDER{
+                let head = addr & !((PAGE_SIZE << order) - 1) ;
+                if head >= self.alloc_start{
+                    let p = self.descriptor(head) ;
+                    if (*p).is_free_head() && (*p).get_order() == order{
+                        self.remove_free(head , order) ;
+                        (*self.descriptor(addr)).set_flag(PageBits::Reserved) ;
+                        self.free_range(head , (addr - head) / PAGE_SIZE) ;
+                        self.free_range(addr + PAGE_SIZE , (head + (PAGE_SIZE << order) - addr) / PAGE_SIZE - 1) ;
+                        return ;
                     }
-                    j += 1 ;
                 }
+                order += 1 ;
+            }
+            panic!("reserve: free page {:#x} is not in any buddy block" , addr) ;
+        }
+    }
+
+    // Is addr inside a reserved range ?
+    pub fn is_reserved(&self , addr: usize) -> bool{
+        if self.is_initialized() && addr >= self.alloc_start && addr < self.alloc_end{
+            return unsafe{ (*self.descriptor(addr)).is_reserved() } ;
+        }
+        let mut r = 0 ;
+        while r < self.num_reserved{
+            let (start , end) = self.reserved[r] ;
+            if addr >= start && addr < end{
+                return true ;
+            }
+            r += 1 ;
+        }
+        false
+    }
+
+    // Contiguous allocation whose physical address is aligned to 2^align_order bytes
+    // (e.g. 21 for a 2 MiB megapage). Anything below PAGE_ORDER is just page aligned.
+    // Runs come back with the usual Taken/Last descriptors, so dealloc frees them.
+    pub fn alloc_aligned(&mut self , pages: usize , align_order: usize) -> *mut u8{
+        assert!(pages > 0) ;
+        // A buddy block of order n is always 2^n pages aligned, so we only need a big enough order
+        let align_page_order = align_order.saturating_sub(PAGE_ORDER) ;
+        let order = order_for(pages).max(align_page_order) ;
+        if order > MAX_ORDER{
+            return null_mut() ;
+        }
+        unsafe{
+            // Find the smallest non-empty free list that can hold the request
+            let mut cur = order ;
+            while cur <= MAX_ORDER && self.free_lists[cur] == 0{
+                cur += 1 ;
+            }
+            if cur > MAX_ORDER{
+                return null_mut() ;  //  No block found
+            }
+
+            let addr = self.free_lists[cur] ;
+            self.remove_free(addr , cur) ;
+
+            // Split the block, putting the upper halves back until it has the order we need
+            while cur > order{
+                cur -= 1 ;
+                self.push_free(addr + (PAGE_SIZE << cur) , cur) ;
+            }
+
+            // The block may be bigger than the request, give the tail back
+            self.free_range(addr + pages * PAGE_SIZE , (1 << order) - pages) ;
+
+            let ptr = self.descriptor(addr) ;
+            let mut i = 0 ;
+            while i < pages{
+                (*ptr.add(i)).set_flag(PageBits::Taken) ;
+                i += 1 ;
             }
+            // Set the last flag
+            (*ptr.add(pages - 1)).set_flag(PageBits::Last) ;
+            (*ptr).refs = 1 ;
+
+            addr as *mut u8
+        }
+    }
+
+    // We want to do a contiguous allocation for the requested number of pages
+    pub fn alloc(&mut self , pages: usize) -> *mut u8{
+        self.alloc_aligned(pages , PAGE_ORDER)
+    }
+
+    pub fn zero_alloc(&mut self , pages: usize) -> *mut u8{
+        zero_pages(self.alloc(pages) , pages)
+    }
+
+    pub fn zero_alloc_aligned(&mut self , pages: usize , align_order: usize) -> *mut u8{
+        zero_pages(self.alloc_aligned(pages , align_order) , pages)
+    }
+
+    // The descriptor of the run starting at addr, or why addr isn't the first page of a live run
+    fn find_run_head(&self , addr: usize) -> Result<*mut Page , BadRun>{
+        if addr == 0{
+            return Err(BadRun::Null) ;
+        }
+        if addr < self.alloc_start || addr >= self.alloc_end{
+            return Err(BadRun::Outside) ;
+        }
+        if !addr.is_multiple_of(PAGE_SIZE){
+            return Err(BadRun::Misaligned) ;
+        }
 
-            if flag{
-                let mut j = i ;
-                while j < i + pages && j < num_pages{
-                    (*ptr.add(j)).set_flag(PageBits::Taken) ;
-                    j += 1 ;
+        unsafe{
+            // If the page before us is taken but isn't the last page of its run, we are in the middle of a run
+            if addr > self.alloc_start{
+                let prev = self.descriptor(addr - PAGE_SIZE) ;
+                if (*prev).is_taken() && !(*prev).is_last(){
+                    return Err(BadRun::Interior) ;
                 }
+            }
+
+            let p = self.descriptor(addr) ;
+            if !(*p).is_taken(){
+                return Err(BadRun::Free) ;
+            }
+            Ok(p)
+        }
+    }
+
+    // find_run_head that panics on a bad address.
+    // "what" is the caller's name so the panic says who tripped over the bad pointer.
+    fn run_head(&self , addr: usize , what: &str) -> *mut Page{
+        match self.find_run_head(addr){
+            Ok(p) => p ,
+            Err(BadRun::Null) => panic!("{}: null pointer" , what) ,
+            Err(BadRun::Outside) => panic!(
+                "{}: {:#x} is outside the page heap ({:#x}..{:#x})" ,
+                what ,
+                addr ,
+                self.alloc_start ,
+                self.alloc_end
+            ) ,
+            Err(BadRun::Misaligned) => panic!("{}: {:#x} is not page aligned" , what , addr) ,
+            Err(BadRun::Interior) => panic!("{}: {:#x} points into the middle of an allocation" , what , addr) ,
+            Err(BadRun::Free) => panic!("{}: {:#x} is not allocated (double free?)" , what , addr) ,
+        }
+    }
+
+    // Release the run starting at addr whose head descriptor is p
+    unsafe fn free_run(&mut self , addr: usize , p: *mut Page){
+        unsafe{
+            // Clear every page of the run except the last one
+            let mut p = p ;
+            let mut pages = 1 ;
+            while (*p).is_taken() && !(*p).is_last(){
+                (*p).clear() ;
+                p = p.add(1) ;
+                pages += 1 ;
+            }
+
+            // The run must be terminated by a Last page, anything else means the descriptors are corrupt
+            assert!((*p).is_last() , "dealloc: run starting at {:#x} has no Last page" , addr) ;
+            (*p).clear() ;
+
+            // Give the pages back to the buddy lists, merging with free neighbours
+            self.free_range(addr , pages) ;
+        }
+    }
 
-                // Set the last and taken flag
-                (*ptr.add(i + pages - 1)).set_flag(PageBits::Last) ;
-                (*ptr.add(i+pages-1)).set_flag(PageBits::Taken) ;
+    // Free a contiguous run of pages previously returned by alloc / zero_alloc.
+    // ptr must be the first page of the run, we walk the descriptors until we hit the one marked Last.
+    // Shared runs (get was called on them) must be released with put instead.
+    pub fn dealloc(&mut self , ptr: *mut u8){
+        unsafe{
+            let p = self.run_head(ptr as usize , "dealloc") ;
+            assert!(
+                (*p).refs <= 1 ,
+                "dealloc: {:#x} still has {} references, use put" ,
+                ptr as usize ,
+                (*p).refs
+            ) ;
+            self.free_run(ptr as usize , p) ;
+        }
+    }
+
+    // Reference counting. A run starts with one reference when alloc hands it out.
+    // The count lives in the descriptor of the first page, so a shared frame is
+    // identified by the address alloc returned (for single pages that is just the page).
+
+    // Take another reference to the run at ptr (e.g. when mapping it into a second page table)
+    pub fn get(&mut self , ptr: *mut u8){
+        unsafe{
+            let p = self.run_head(ptr as usize , "get") ;
+            assert!((*p).refs < u16::MAX , "get: reference count overflow on {:#x}" , ptr as usize) ;
+            (*p).refs += 1 ;
+        }
+    }
+
+    // Drop a reference to the run at ptr, the pages are freed when the last one goes away.
+    // Returns true if this call freed the run.
+    pub fn put(&mut self , ptr: *mut u8) -> bool{
+        unsafe{
+            let p = self.run_head(ptr as usize , "put") ;
+            (*p).refs -= 1 ;
+            if (*p).refs == 0{
+                self.free_run(ptr as usize , p) ;
+                true
+            }
+            else{
+                false
+            }
+        }
+    }
+
+    // Number of references held on the run at ptr
+    pub fn ref_count(&self , ptr: *mut u8) -> usize{
+        unsafe{
+            (*self.run_head(ptr as usize , "ref_count")).refs as usize
+        }
+    }
+
+    // ref_count that returns None instead of panicking when ptr isn't the start of a live run
+    pub fn try_ref_count(&self , ptr: *mut u8) -> Option<usize>{
+        self.find_run_head(ptr as usize).ok().map(|p| unsafe{ (*p).refs as usize })
+    }
 
-                return (ALLOC_START + i * PAGE_SIZE) as *mut u8 ;
+    // Walk the descriptors and count what is taken and what is free
+    pub fn stats(&self) -> PageStats{
+        let total = (self.alloc_end - self.alloc_start) / PAGE_SIZE ;
+        let ptr = self.desc as *const Page ;
+        let mut used = 0 ;
+        let mut reserved = 0 ;
+        let mut run = 0 ;
+        let mut largest_free_run = 0 ;
+        let mut i = 0 ;
+        while i < total{
+            let page = unsafe{ &*ptr.add(i) } ;
+            if page.is_taken(){
+                used += 1 ;
+                run = 0 ;
+            }
+            else if page.is_reserved(){
+                reserved += 1 ;
+                run = 0 ;
+            }
+            else{
+                run += 1 ;
+                if run > largest_free_run{
+                    largest_free_run = run ;
+                }
+            }
+            i += 1 ;
+        }
+        PageStats{
+            total ,
+            used ,
+            reserved ,
+            free: total - used - reserved ,
+            largest_free_run ,
+        }
+    }
 
+    // Call f(start , pages) for every allocated run, in address order
+    pub fn for_each_run<F: FnMut(usize , usize)>(&self , mut f: F){
+        let total = (self.alloc_end - self.alloc_start) / PAGE_SIZE ;
+        let ptr = self.desc as *const Page ;
+        let mut i = 0 ;
+        while i < total{
+            unsafe{
+                if (*ptr.add(i)).is_taken(){
+                    let first = i ;
+                    // Walk to the Last page of this run
+                    while i < total && !(*ptr.add(i)).is_last(){
+                        i += 1 ;
+                    }
+                    f(self.alloc_start + first * PAGE_SIZE , i + 1 - first) ;
+                }
             }
             i += 1 ;
         }
     }
-    null_mut()  //  No page found
+
+    // Print every allocated run (start , end , number of pages) followed by the totals
+    pub fn print_allocations(&self){
+        let total = (self.alloc_end - self.alloc_start) / PAGE_SIZE ;
+        let meta_end = self.desc + total * size_of::<Page>() ;
+        println!() ;
+        println!("PAGE ALLOCATION TABLE") ;
+        println!("META: {:#x} -> {:#x}" , self.desc , meta_end) ;
+        println!("PHYS: {:#x} -> {:#x}" , self.alloc_start , self.alloc_end) ;
+        println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~") ;
+
+        self.for_each_run(|start , pages| {
+            println!("{:#x} => {:#x}: {} page(s)" , start , start + pages * PAGE_SIZE - 1 , pages) ;
+        }) ;
+
+        let s = self.stats() ;
+        println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~") ;
+        println!("Allocated: {:>6} pages ({:>10} bytes)." , s.used , s.used * PAGE_SIZE) ;
+        println!("Reserved : {:>6} pages ({:>10} bytes)." , s.reserved , s.reserved * PAGE_SIZE) ;
+        println!("Free     : {:>6} pages ({:>10} bytes)." , s.free , s.free * PAGE_SIZE) ;
+        println!("Largest free run: {} pages" , s.largest_free_run) ;
+        println!() ;
+    }
 }
 
-pub fn zero_alloc(pages:usize) -> *mut u8{
-    let ret = alloc(pages) ;
+fn zero_pages(ret: *mut u8 , pages: usize) -> *mut u8{
     if !ret.is_null(){
         let size = (PAGE_SIZE * pages)/8  ;
         let big_ptr = ret as *mut u64 ; // This is to force sd instruction and lower the number of stores
@@ -130,137 +734,1707 @@ pub fn zero_alloc(pages:usize) -> *mut u8{
     }
     ret
 }
-// pub fn dealloc(ptr: *mut u8){
-//
-// }
-
-#[repr(i64)]  // Represent our entry bits as unsigned 64-bits integers
-#[derive(Copy , Clone)] // Automatically derive Copy and Clone traits for our enum
-pub enum EntryBits{
-    // D|A|G|U|X|W|R|V
-    None = 0 ,
-    Valid = 1 << 0 ,
-    Read = 1 << 1 ,
-    Write = 1 << 2 ,
-    Execute = 1 << 3 ,
-    User = 1 << 4 ,
-    Global = 1 << 5 ,
-    Access = 1 << 6 ,
-    Dirty = 1 << 7 ,
-    ReadWrite = 1 << 1 | 1 << 2,
-    ReadExecute = 1 << 1 | 1 << 3,
-    ReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3,
-    UserReadWrite = 1 << 1 | 1 << 2 | 1 << 4,
-    UserReadExecute = 1 << 1 | 1 << 3 | 1 << 4,
-    UserReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3 | 1 << 4,
-}
-
-impl EntryBits{
-    pub fn val(self)-> i64{
-        self as i64
+
+// The raw pointers in a PageHeap only point into its own region, whoever owns the heap owns those
+unsafe impl Send for PageHeap{}
+
+// The kernel's page heap, set up by init over HEAP_START
+static PAGE_HEAP: Mutex<PageHeap> = Mutex::new(PageHeap::empty()) ;
+
+// Every wrapper below holds the lock for just the one call
+fn heap() -> MutexGuard<'static , PageHeap>{
+    PAGE_HEAP.lock()
+}
+
+// dtb is the device tree pointer the firmware left in a1, or 0 if there is none.
+// With a DTB the heap runs from HEAP_START to the end of the /memory range holding it, and the
+// blob itself plus /reserved-memory are kept out of the allocator. Without one we use the linker symbols.
+// Only that one range is used: the heap is a single region with one descriptor table, so RAM in
+// other /memory banks (or before HEAP_START) is left alone.
+// Ranges passed to reserve() before init are applied here too, before anything can be allocated.
+#[cfg(not(test))]
+pub fn init(dtb: usize){
+    unsafe{
+        heap().init_with_fdt(HEAP_START , HEAP_START + HEAP_SIZE , dtb) ;
     }
 }
 
-pub struct Entry{
-    pub entry: i64 ,
+// The wrappers that allocate are #[track_caller] with the alloc_track feature, so
+// track.rs can record each run under the location of whoever asked for it.
+
+// Pages for the allocators' and the page tables' own use. They aren't recorded in track.rs,
+// where they would show up as leaks at an allocator line every time a heap or a table grows.
+pub(crate) fn zero_alloc_internal(pages: usize , align_order: usize) -> *mut u8{
+    heap().zero_alloc_aligned(pages , align_order)
 }
 
-impl Entry{
-    pub fn is_valid(&self) -> bool{
-        if self.get_entry() & EntryBits::Valid.val() != 0{
-            true
-        }
-        else{
-            false
-        }
-    }
+// See PageHeap::reserve
+pub fn reserve(start: usize , end: usize) -> bool{
+    heap().reserve(start , end)
+}
 
-    pub fn is_leaf(&self) -> bool{
-        // Check if any one of RWX bits is set
-        if self.get_entry() & 0xE != 0{
-            true
+pub fn is_reserved(addr: usize) -> bool{
+    heap().is_reserved(addr)
+}
+
+pub fn get_alloc_start() -> usize{
+    heap().get_alloc_start()
+}
+
+pub fn get_alloc_end() -> usize{
+    heap().get_alloc_end()
+}
+
+#[cfg_attr(feature = "alloc_track" , track_caller)]
+pub fn alloc(pages: usize) -> *mut u8{
+    let ret = heap().alloc(pages) ;
+    #[cfg(feature = "alloc_track")]
+    crate::track::add(ret , PAGE_SIZE * pages , crate::track::Kind::Pages , core::panic::Location::caller()) ;
+    ret
+}
+
+#[cfg_attr(feature = "alloc_track" , track_caller)]
+pub fn alloc_aligned(pages: usize , align_order: usize) -> *mut u8{
+    let ret = heap().alloc_aligned(pages , align_order) ;
+    #[cfg(feature = "alloc_track")]
+    crate::track::add(ret , PAGE_SIZE * pages , crate::track::Kind::Pages , core::panic::Location::caller()) ;
+    ret
+}
+
+#[cfg_attr(feature = "alloc_track" , track_caller)]
+pub fn zero_alloc(pages:usize) -> *mut u8{
+    let ret = heap().zero_alloc(pages) ;
+    #[cfg(feature = "alloc_track")]
+    crate::track::add(ret , PAGE_SIZE * pages , crate::track::Kind::Pages , core::panic::Location::caller()) ;
+    ret
+}
+
+#[cfg_attr(feature = "alloc_track" , track_caller)]
+pub fn zero_alloc_aligned(pages: usize , align_order: usize) -> *mut u8{
+    let ret = heap().zero_alloc_aligned(pages , align_order) ;
+    #[cfg(feature = "alloc_track")]
+    crate::track::add(ret , PAGE_SIZE * pages , crate::track::Kind::Pages , core::panic::Location::caller()) ;
+    ret
+}
+
+#[cfg_attr(feature = "alloc_track" , track_caller)]
+pub fn try_alloc_pages(pages: usize) -> Result<*mut u8 , AllocFailure>{
+    try_alloc_pages_aligned(pages , PAGE_ORDER)
+}
+
+#[cfg_attr(feature = "alloc_track" , track_caller)]
+pub fn try_alloc_pages_aligned(pages: usize , align_order: usize) -> Result<*mut u8 , AllocFailure>{
+    try_alloc_pages_with(pages , align_order , false)
+}
+
+#[cfg_attr(feature = "alloc_track" , track_caller)]
+pub fn try_zero_alloc_pages_aligned(pages: usize , align_order: usize) -> Result<*mut u8 , AllocFailure>{
+    try_alloc_pages_with(pages , align_order , true)
+}
+
+#[cfg_attr(feature = "alloc_track" , track_caller)]
+fn try_alloc_pages_with(pages: usize , align_order: usize , zero: bool) -> Result<*mut u8 , AllocFailure>{
+    if pages == 0 || pages > (1 << MAX_ORDER) || align_order >= usize::BITS as usize{
+        return Err(AllocFailure::BadLayout) ;
+    }
+    let ret = oom::retry(pages * PAGE_SIZE , || {
+        if zero{
+            heap().zero_alloc_aligned(pages , align_order)
         }
         else{
-            false
+            heap().alloc_aligned(pages , align_order)
         }
+    }) ;
+    if ret.is_null(){
+        return Err(AllocFailure::OutOfMemory{ size: pages * PAGE_SIZE , align: 1 << align_order.max(PAGE_ORDER) }) ;
     }
-    // Getters and Setters
-    pub fn set_entry(&mut self , entry: i64){
-        self.entry = entry ;
-    }
+    #[cfg(feature = "alloc_track")]
+    crate::track::add(ret , PAGE_SIZE * pages , crate::track::Kind::Pages , core::panic::Location::caller()) ;
+    Ok(ret)
+}
 
-    pub fn get_entry(&self) -> i64{
-        self.entry
-    }
+pub fn dealloc(ptr: *mut u8){
+    heap().dealloc(ptr) ;
+    #[cfg(feature = "alloc_track")]
+    crate::track::remove(ptr , crate::track::Kind::Pages) ;
 }
 
-pub struct Table{
-    pub entries: [Entry; 512] ,  // 512 entries in the table (2^9)
+pub fn get(ptr: *mut u8){
+    heap().get(ptr)
 }
 
-impl Table{
-    pub fn len() -> usize{
-        512
+pub fn put(ptr: *mut u8) -> bool{
+    let freed = heap().put(ptr) ;
+    #[cfg(feature = "alloc_track")]
+    if freed{
+        crate::track::remove(ptr , crate::track::Kind::Pages) ;
     }
+    freed
 }
 
-// We'll take the reference to root table , va , pa , bits -->
-pub fn mapping(root: &mut Table , va: usize , pa:usize , bits:i64 , level:usize){
+pub fn ref_count(ptr: *mut u8) -> usize{
+    heap().ref_count(ptr)
+}
 
-    // Check if we RWX have been provided
-    assert!(bits & 0xE != 0) ;
+pub fn try_ref_count(ptr: *mut u8) -> Option<usize>{
+    heap().try_ref_count(ptr)
+}
 
-    let vpn = [(va >> 12) & 0x1FF , (va >> 21) & 0x1FF , (va >> 30) & 0x1FF] ;
-    // First 12 bits are offset
-    // VPN[0] = bits from 20:12
-    // VPN[1] = bits from 29:21
-    // VPN[2] = bits from 38:30 (sv39)
+pub fn stats() -> PageStats{
+    heap().stats()
+}
 
-    let ppn = [(pa >> 12) & 0x1FF , (pa >> 21) & 0x1FF , (pa >> 30) & 0x3FF_FFFF] ;
-    // PPN[0] = bits from 20:12
-    // PPN[1] = bits from 29:21
-    // PPN[2] = bits from 55:30 (sv39)
+pub fn print_page_allocations(){
+    heap().print_allocations()
+}
 
-    // Page table walk
-    let mut v = &mut root.entries[vpn[2]] ;
+// core::alloc::Allocator over the kernel page heap, for collections that want whole pages,
+// e.g. Box::new_in(Table{..} , PageAllocator) or Vec::with_capacity_in(n , PageAllocator).
+// Every allocation is rounded up to whole pages and aligned to at least PAGE_SIZE.
+#[derive(Copy , Clone , Default , Debug)]
+pub struct PageAllocator ;
 
-    for i in (level..2).rev(){
-        if !v.is_valid(){
-            let page = zero_alloc(1) ;
-            v.set_entry((page as i64 >> 2) | EntryBits::Valid.val() ,) ;
+impl PageAllocator{
+    #[cfg_attr(feature = "alloc_track" , track_caller)]
+    fn alloc_impl(layout: Layout , zeroed: bool) -> Result<NonNull<[u8]> , AllocError>{
+        if layout.size() == 0{
+            // Nothing to hand out, any well aligned non null pointer will do
+            let dangling = NonNull::new(layout.align() as *mut u8).ok_or(AllocError)? ;
+            return Ok(NonNull::slice_from_raw_parts(dangling , 0)) ;
         }
-        let entry = ((v.get_entry() & !0x3FF) << 2) as *mut Entry ;
-        v = unsafe{
-            entry.add(vpn[i]).as_mut().unwrap()
-        };
+        let pages = layout.size().div_ceil(PAGE_SIZE) ;
+        let align_order = layout.align().max(PAGE_SIZE).trailing_zeros() as usize ;
+        let ptr = try_alloc_pages_with(pages , align_order , zeroed)? ;
+        let ptr = NonNull::new(ptr).ok_or(AllocError)? ;
+        Ok(NonNull::slice_from_raw_parts(ptr , pages * PAGE_SIZE))
     }
-    // Make the leaf point to the physical page
-    let entry = (ppn[2] << 28 ) as i64 | (ppn[1] << 19) as i64 | (ppn[0] << 10) as i64 | bits | EntryBits::Valid.val() ;
-    v.set_entry(entry) ;
 }
 
-pub fn translate(root: &Table , va:usize) -> Option<usize>{
-    let vpn = [(va >> 12) & 0x1FF , (va >> 21) & 0x1FF , (va >> 30) & 0x1FF] ;
-    let mut v = &root.entries[vpn[2]] ;
+unsafe impl Allocator for PageAllocator{
+    #[cfg_attr(feature = "alloc_track" , track_caller)]
+    fn allocate(&self , layout: Layout) -> Result<NonNull<[u8]> , AllocError>{
+        Self::alloc_impl(layout , false)
+    }
 
-    for i in (0..=2).rev(){
-        if !v.is_valid(){
-            // Not a valid entry --> Page Fault
-            break ;
-        }
-        else if v.is_leaf(){
-            let offset = (1 << (12 + i * 9)) - 1  as i64; // 12 + 0 = 12 , 12 + 9 = 21 , 12 + 18 = 30
-            let pageoffset = va & (offset as usize) ;
-            let addr = (v.get_entry() << 2 as usize) & !offset ;
-            return Some(addr as usize | pageoffset ) ;
+    #[cfg_attr(feature = "alloc_track" , track_caller)]
+    fn allocate_zeroed(&self , layout: Layout) -> Result<NonNull<[u8]> , AllocError>{
+        Self::alloc_impl(layout , true)
+    }
+
+    unsafe fn deallocate(&self , ptr: NonNull<u8> , layout: Layout){
+        if layout.size() != 0{
+            dealloc(ptr.as_ptr()) ;
         }
+    }
+}
 
-        let entry = ((v.get_entry() & !0x3FF) << 2) as *const Entry ;
+// Addresses and page numbers get types of their own, so handing a virtual address to
+// something that wants a physical one (or a PPN to something that wants an address)
+// doesn't compile without saying so.
+#[repr(transparent)]
+#[derive(Copy , Clone , PartialEq , Eq , PartialOrd , Ord , Hash , Default)]
+pub struct PhysAddr(usize) ;
 
-        // Set v properly
-        v = unsafe{
-            entry.add(vpn[i-1]).as_ref().unwrap()
-        };
+#[repr(transparent)]
+#[derive(Copy , Clone , PartialEq , Eq , PartialOrd , Ord , Hash , Default)]
+pub struct VirtAddr(usize) ;
+
+// Physical page number, the address >> 12. This is what PTEs and satp hold.
+#[repr(transparent)]
+#[derive(Copy , Clone , PartialEq , Eq , PartialOrd , Ord , Hash , Default)]
+pub struct PageNum(usize) ;
+
+// What PhysAddr and VirtAddr have in common. align is a power of two.
+macro_rules! address{
+    ($t:ident) => {
+        impl $t{
+            pub const fn new(addr: usize) -> Self{
+                $t(addr)
+            }
+
+            pub const fn as_usize(self) -> usize{
+                self.0
+            }
+
+            pub const fn align_down(self , align: usize) -> Self{
+                $t(self.0 & !(align - 1))
+            }
+
+            pub const fn align_up(self , align: usize) -> Self{
+                $t((self.0 + align - 1) & !(align - 1))
+            }
+
+            pub const fn is_aligned(self , align: usize) -> bool{
+                self.0 & (align - 1) == 0
+            }
+
+            // Offset into the 4 KiB page
+            pub const fn page_offset(self) -> usize{
+                self.0 & (PAGE_SIZE - 1)
+            }
+
+            pub const fn checked_add(self , bytes: usize) -> Option<Self>{
+                match self.0.checked_add(bytes){
+                    Some(addr) => Some($t(addr)) ,
+                    None => None ,
+                }
+            }
+        }
+
+        impl Add<usize> for $t{
+            type Output = $t ;
+            fn add(self , bytes: usize) -> $t{
+                $t(self.0 + bytes)
+            }
+        }
+
+        impl AddAssign<usize> for $t{
+            fn add_assign(&mut self , bytes: usize){
+                self.0 += bytes ;
+            }
+        }
+
+        // Bytes between two addresses of the same kind
+        impl Sub<$t> for $t{
+            type Output = usize ;
+            fn sub(self , other: $t) -> usize{
+                self.0 - other.0
+            }
+        }
+
+        impl fmt::Debug for $t{
+            fn fmt(&self , f: &mut fmt::Formatter) -> fmt::Result{
+                write!(f , concat!(stringify!($t) , "({:#x})") , self.0)
+            }
+        }
+
+        impl fmt::LowerHex for $t{
+            fn fmt(&self , f: &mut fmt::Formatter) -> fmt::Result{
+                fmt::LowerHex::fmt(&self.0 , f)
+            }
+        }
+    };
+}
+
+address!(PhysAddr) ;
+address!(VirtAddr) ;
+
+impl PhysAddr{
+    // The kernel runs with memory identity mapped (or with translation off), so a physical
+    // address is also a pointer we can use, and the other way round
+    pub fn from_ptr<T>(ptr: *const T) -> Self{
+        PhysAddr(ptr as usize)
+    }
+
+    pub const fn as_ptr<T>(self) -> *mut T{
+        self.0 as *mut T
+    }
+
+    pub const fn page_num(self) -> PageNum{
+        PageNum(self.0 >> PAGE_ORDER)
+    }
+}
+
+impl VirtAddr{
+    pub fn from_ptr<T>(ptr: *const T) -> Self{
+        VirtAddr(ptr as usize)
+    }
+
+    // VPN[level], bits 12 + 9 * level + 8 : 12 + 9 * level
+    pub const fn vpn(self , level: usize) -> usize{
+        (self.0 >> (12 + 9 * level)) & 0x1FF
+    }
+}
+
+impl PageNum{
+    pub const fn new(ppn: usize) -> Self{
+        PageNum(ppn)
+    }
+
+    pub const fn as_usize(self) -> usize{
+        self.0
+    }
+
+    // Address of the first byte of the page
+    pub const fn addr(self) -> PhysAddr{
+        PhysAddr(self.0 << PAGE_ORDER)
+    }
+}
+
+impl Add<usize> for PageNum{
+    type Output = PageNum ;
+    fn add(self , pages: usize) -> PageNum{
+        PageNum(self.0 + pages)
+    }
+}
+
+impl fmt::Debug for PageNum{
+    fn fmt(&self , f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f , "PageNum({:#x})" , self.0)
+    }
+}
+
+// The flag bits of a PTE, RSW|D|A|G|U|X|W|R|V. Combine them with | and test them with contains.
+#[repr(transparent)]
+#[derive(Copy , Clone , PartialEq , Eq , Hash , Default)]
+pub struct PteFlags(u64) ;
+
+impl PteFlags{
+    pub const VALID: PteFlags = PteFlags(1 << 0) ;
+    pub const READ: PteFlags = PteFlags(1 << 1) ;
+    pub const WRITE: PteFlags = PteFlags(1 << 2) ;
+    pub const EXECUTE: PteFlags = PteFlags(1 << 3) ;
+    pub const USER: PteFlags = PteFlags(1 << 4) ;
+    pub const GLOBAL: PteFlags = PteFlags(1 << 5) ;
+    pub const ACCESSED: PteFlags = PteFlags(1 << 6) ;
+    pub const DIRTY: PteFlags = PteFlags(1 << 7) ;
+    // The two bits the hardware leaves to supervisor software
+    pub const RSW0: PteFlags = PteFlags(1 << 8) ;
+    pub const RSW1: PteFlags = PteFlags(1 << 9) ;
+
+    pub const RW: PteFlags = Self::READ.union(Self::WRITE) ;
+    pub const RX: PteFlags = Self::READ.union(Self::EXECUTE) ;
+    pub const RWX: PteFlags = Self::RW.union(Self::EXECUTE) ;
+    pub const USER_RW: PteFlags = Self::RW.union(Self::USER) ;
+    pub const USER_RX: PteFlags = Self::RX.union(Self::USER) ;
+    pub const USER_RWX: PteFlags = Self::RWX.union(Self::USER) ;
+
+    const ALL: u64 = 0x3FF ;
+
+    const NAMES: [(&'static str , PteFlags) ; 10] = [
+        ("VALID" , Self::VALID) , ("READ" , Self::READ) , ("WRITE" , Self::WRITE) ,
+        ("EXECUTE" , Self::EXECUTE) , ("USER" , Self::USER) , ("GLOBAL" , Self::GLOBAL) ,
+        ("ACCESSED" , Self::ACCESSED) , ("DIRTY" , Self::DIRTY) , ("RSW0" , Self::RSW0) , ("RSW1" , Self::RSW1) ,
+    ] ;
+
+    pub const fn empty() -> Self{
+        PteFlags(0)
+    }
+
+    pub const fn bits(self) -> u64{
+        self.0
+    }
+
+    // None if bits has anything set that isn't a flag
+    pub const fn from_bits(bits: u64) -> Option<Self>{
+        if bits & !Self::ALL != 0{
+            None
+        }
+        else{
+            Some(PteFlags(bits))
+        }
+    }
+
+    // Drops whatever isn't a flag (the PPN of a raw entry, say)
+    pub const fn from_bits_truncate(bits: u64) -> Self{
+        PteFlags(bits & Self::ALL)
+    }
+
+    pub const fn is_empty(self) -> bool{
+        self.0 == 0
+    }
+
+    // Every flag of other is set
+    pub const fn contains(self , other: PteFlags) -> bool{
+        self.0 & other.0 == other.0
+    }
+
+    // Any flag of other is set
+    pub const fn intersects(self , other: PteFlags) -> bool{
+        self.0 & other.0 != 0
+    }
+
+    pub const fn union(self , other: PteFlags) -> Self{
+        PteFlags(self.0 | other.0)
+    }
+
+    pub const fn difference(self , other: PteFlags) -> Self{
+        PteFlags(self.0 & !other.0)
+    }
+
+    // An entry with any of RWX set is a leaf, with none a pointer to the next table
+    pub const fn is_leaf(self) -> bool{
+        self.intersects(Self::RWX)
+    }
+}
+
+impl BitOr for PteFlags{
+    type Output = PteFlags ;
+    fn bitor(self , other: PteFlags) -> PteFlags{
+        self.union(other)
+    }
+}
+
+impl BitOrAssign for PteFlags{
+    fn bitor_assign(&mut self , other: PteFlags){
+        self.0 |= other.0 ;
+    }
+}
+
+impl BitAnd for PteFlags{
+    type Output = PteFlags ;
+    fn bitand(self , other: PteFlags) -> PteFlags{
+        PteFlags(self.0 & other.0)
+    }
+}
+
+impl BitAndAssign for PteFlags{
+    fn bitand_assign(&mut self , other: PteFlags){
+        self.0 &= other.0 ;
+    }
+}
+
+impl Not for PteFlags{
+    type Output = PteFlags ;
+    fn not(self) -> PteFlags{
+        PteFlags(!self.0 & Self::ALL)
+    }
+}
+
+// PteFlags(READ | WRITE | VALID) style, like the bitflags crate prints them
+impl fmt::Debug for PteFlags{
+    fn fmt(&self , f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f , "PteFlags(")? ;
+        let mut first = true ;
+        for (name , flag) in Self::NAMES.iter(){
+            if self.contains(*flag){
+                write!(f , "{}{}" , if first { "" } else { " | " } , name)? ;
+                first = false ;
+            }
+        }
+        if first{
+            write!(f , "empty")? ;
+        }
+        write!(f , ")")
+    }
+}
+
+// RWXUGAD with a - for every flag that is clear, the way the page table dump shows them
+impl fmt::Display for PteFlags{
+    fn fmt(&self , f: &mut fmt::Formatter) -> fmt::Result{
+        let letters = [
+            ('R' , Self::READ) , ('W' , Self::WRITE) , ('X' , Self::EXECUTE) , ('U' , Self::USER) ,
+            ('G' , Self::GLOBAL) , ('A' , Self::ACCESSED) , ('D' , Self::DIRTY) ,
+        ] ;
+        for (c , flag) in letters{
+            write!(f , "{}" , if self.contains(flag) { c } else { '-' })? ;
+        }
+        Ok(())
+    }
+}
+
+pub struct Entry{
+    pub entry: u64 ,
+}
+
+impl Entry{
+    pub fn is_valid(&self) -> bool{
+        self.flags().contains(PteFlags::VALID)
+    }
+
+    pub fn is_leaf(&self) -> bool{
+        // Check if any one of RWX bits is set
+        self.flags().is_leaf()
+    }
+
+    pub fn flags(&self) -> PteFlags{
+        PteFlags::from_bits_truncate(self.entry)
+    }
+
+    // The page a leaf maps, or the next level table of a pointer entry
+    pub fn ppn(&self) -> PageNum{
+        PageNum((self.entry >> 10) as usize & PPN_MASK)
+    }
+
+    pub fn addr(&self) -> PhysAddr{
+        self.ppn().addr()
+    }
+
+    pub fn set(&mut self , ppn: PageNum , flags: PteFlags){
+        self.entry = ((ppn.as_usize() & PPN_MASK) << 10) as u64 | flags.bits() ;
+    }
+
+    pub fn clear(&mut self){
+        self.entry = 0 ;
+    }
+
+    // Raw getters and setters
+    pub fn set_entry(&mut self , entry: u64){
+        self.entry = entry ;
+    }
+
+    pub fn get_entry(&self) -> u64{
+        self.entry
+    }
+}
+
+pub struct Table{
+    pub entries: [Entry; 512] ,  // 512 entries in the table (2^9)
+}
+
+impl Table{
+    pub fn len() -> usize{
+        512
+    }
+}
+
+// Paging modes the page table code can walk. All of them use the same 512 entry tables,
+// they differ in how many levels there are above the 4 KiB pages.
+#[repr(usize)]
+#[derive(Copy , Clone , Debug , PartialEq , Eq)]
+pub enum PagingMode{
+    Sv39 = 8 ,
+    Sv48 = 9 ,
+    Sv57 = 10 ,
+}
+
+impl PagingMode{
+    pub const fn levels(self) -> usize{
+        match self{
+            PagingMode::Sv39 => 3 ,
+            PagingMode::Sv48 => 4 ,
+            PagingMode::Sv57 => 5 ,
+        }
+    }
+
+    // Level of the root table
+    pub const fn top(self) -> usize{
+        self.levels() - 1
+    }
+
+    // satp value for a root table at root (ASID 0)
+    pub const fn satp(self , root: PhysAddr) -> usize{
+        ((self as usize) << 60) | root.page_num().as_usize()
+    }
+
+    fn from_levels(levels: usize) -> Self{
+        match levels{
+            4 => PagingMode::Sv48 ,
+            5 => PagingMode::Sv57 ,
+            _ => PagingMode::Sv39 ,
+        }
+    }
+}
+
+const MAX_LEVELS: usize = 5 ;
+
+// A PTE holds a 44 bit PPN in bits 53:10, whatever the mode
+const PPN_MASK: usize = (1 << 44) - 1 ;
+
+// The mode every page table function works in, set once at boot before the first mapping
+static PAGING_LEVELS: AtomicUsize = AtomicUsize::new(3) ;
+
+pub fn paging_mode() -> PagingMode{
+    PagingMode::from_levels(PAGING_LEVELS.load(Ordering::Relaxed))
+}
+
+pub fn set_paging_mode(mode: PagingMode){
+    PAGING_LEVELS.store(mode.levels() , Ordering::Relaxed) ;
+}
+
+// The deepest mode the hart supports. satp is WARL, a mode the hart doesn't have doesn't stick.
+// Only run this with translation off (kinit in machine mode), it writes satp.
+#[cfg(target_arch = "riscv64")]
+pub fn probe_paging_mode() -> PagingMode{
+    for mode in [PagingMode::Sv57 , PagingMode::Sv48]{
+        let got: usize ;
+        unsafe{
+            core::arch::asm!(
+                "csrw satp, {0}" ,
+                "csrr {1}, satp" ,
+                "csrw satp, zero" ,
+                in(reg) mode.satp(PhysAddr::new(0)) ,
+                out(reg) got ,
+            ) ;
+        }
+        if got >> 60 == mode as usize{
+            return mode ;
+        }
+    }
+    PagingMode::Sv39
+}
+
+// Nothing to probe when the page tables are tested on the host
+#[cfg(not(target_arch = "riscv64"))]
+pub fn probe_paging_mode() -> PagingMode{
+    PagingMode::Sv39
+}
+
+// We'll take the reference to root table , va , pa , flags -->
+// level 0 maps a 4 KiB page , 1 a 2 MiB megapage , 2 a 1 GiB gigapage (and so on up in Sv48 / Sv57),
+// va and pa must be aligned to that.
+// A large leaf on the way down is split up. A large leaf can only go where a table is if that
+// table is empty (it is freed then), the mappings under a table that isn't have to be unmapped first.
+// Panics if there is no page for a table or a table is in the way, see try_mapping.
+pub fn mapping(root: &mut Table , va: VirtAddr , pa: PhysAddr , flags: PteFlags , level:usize){
+    mapping_in(paging_mode() , root , va , pa , flags , level)
+}
+
+// Why try_mapping mapped nothing
+#[derive(Copy , Clone , Debug , PartialEq , Eq)]
+pub enum MapError{
+    OutOfMemory ,      // No page for a table or to split a large leaf
+    Mapped(VirtAddr) , // The large leaf at va would replace a table that still maps something
+}
+
+// mapping that returns Err when it runs out of pages for a table or a split, or a table is in the
+// way. The tables it made on the way down go back then, the ones it split still map what they did,
+// so nothing changes.
+pub fn try_mapping(root: &mut Table , va: VirtAddr , pa: PhysAddr , flags: PteFlags , level:usize) -> Result<() , MapError>{
+    try_mapping_in(paging_mode() , root , va , pa , flags , level)
+}
+
+fn mapping_in(mode: PagingMode , root: &mut Table , va: VirtAddr , pa: PhysAddr , flags: PteFlags , level:usize){
+    match try_mapping_in(mode , root , va , pa , flags , level){
+        Ok(()) => {}
+        Err(MapError::OutOfMemory) => panic!("mapping: no page for a page table") ,
+        Err(MapError::Mapped(va)) => panic!("mapping: {:#x} has mappings under it, unmap them first" , va) ,
+    }
+}
+
+fn try_mapping_in(mode: PagingMode , root: &mut Table , va: VirtAddr , pa: PhysAddr , flags: PteFlags , level:usize) -> Result<() , MapError>{
+
+    // Check if we RWX have been provided
+    assert!(flags.is_leaf()) ;
+    let top = mode.top() ;
+    assert!(level <= top && va.is_aligned(level_size(level)) && pa.is_aligned(level_size(level)) ,
+            "mapping: {:#x} -> {:#x} is not aligned for a level {} leaf" , va , pa , level) ;
+
+    // Page table walk, starting at the root's VPN
+    let mut v = &mut root.entries[va.vpn(top)] ;
+    // The first entry we pointed at a new table (and its level), everything under it is new too
+    let mut made: Option<(*mut Entry , usize)> = None ;
+
+    for i in (level..top).rev(){
+        let ok = if !v.is_valid(){
+            let page = alloc_table() ;
+            if !page.is_null(){
+                v.set(PhysAddr::from_ptr(page).page_num() , PteFlags::VALID) ;
+                made = made.or(Some((v as *mut Entry , i))) ;
+            }
+            !page.is_null()
+        }
+        else if v.is_leaf(){
+            // v is on level i + 1
+            split_leaf(v , i + 1)
+        }
+        else{
+            true
+        } ;
+        if !ok{
+            if let Some((e , i)) = made{
+                unsafe{
+                    free_table(next_table(&*e) , i) ;
+                    (*e).clear() ;
+                }
+            }
+            return Err(MapError::OutOfMemory) ;
+        }
+        let entry = v.addr().as_ptr::<Entry>() ;
+        v = unsafe{
+            entry.add(va.vpn(i)).as_mut().unwrap()
+        };
+    }
+    if level > 0 && v.is_valid() && !v.is_leaf(){
+        // The leaf would cover the table, anything still mapped under it has to go first
+        if unsafe{ !(*next_table(v)).is_empty() }{
+            return Err(MapError::Mapped(va)) ;
+        }
+        free_table(next_table(v) , level - 1) ;
+    }
+    // Make the leaf point to the physical page
+    v.set(pa.page_num() , flags | PteFlags::VALID) ;
+    Ok(())
+}
+
+pub fn translate(root: &Table , va: VirtAddr) -> Option<PhysAddr>{
+    translate_in(paging_mode() , root , va)
+}
+
+fn translate_in(mode: PagingMode , root: &Table , va: VirtAddr) -> Option<PhysAddr>{
+    lookup_in(mode , root , va).map(|r| r.pa + (va - r.va))
+}
+
+// The leaf that maps va, whole (va and pa are where it starts)
+pub fn lookup(root: &Table , va: VirtAddr) -> Option<MapRange>{
+    lookup_in(paging_mode() , root , va)
+}
+
+fn lookup_in(mode: PagingMode , root: &Table , va: VirtAddr) -> Option<MapRange>{
+    let mut v = &root.entries[va.vpn(mode.top())] ;
+
+    for i in (0..=mode.top()).rev(){
+        if !v.is_valid(){
+            // Not a valid entry --> Page Fault
+            break ;
+        }
+        else if v.is_leaf(){
+            // A large leaf has to be aligned to its size, otherwise the hardware faults too
+            if !v.addr().is_aligned(level_size(i)){
+                break ;
+            }
+            // level_size is 1 << (12 + 0 = 12 , 12 + 9 = 21 , 12 + 18 = 30 ...)
+            return Some(MapRange{ va: va.align_down(level_size(i)) , pa: v.addr() , size: level_size(i) , flags: v.flags() , level: i }) ;
+        }
+
+        if i == 0{
+            // A pointer on the last level is malformed --> Page Fault
+            break ;
+        }
+        let entry = v.addr().as_ptr::<Entry>() as *const Entry ;
+
+        // Set v properly
+        v = unsafe{
+            entry.add(va.vpn(i - 1)).as_ref().unwrap()
+        };
     }
     None
 }
+
+// The first address in [start , end) that has a leaf, None if nothing in it is mapped.
+// Only the entries covering the range are looked at, a hole is skipped a whole table at a time.
+pub fn first_mapped(root: &Table , start: VirtAddr , end: VirtAddr) -> Option<VirtAddr>{
+    first_mapped_in(paging_mode() , root , start , end)
+}
+
+fn first_mapped_in(mode: PagingMode , root: &Table , start: VirtAddr , end: VirtAddr) -> Option<VirtAddr>{
+    let mut va = start ;
+    while va < end{
+        let mut table = root as *const Table ;
+        let mut level = mode.top() ;
+        // Down to the first entry that isn't valid, the bytes it covers are free
+        let hole = loop{
+            let v = unsafe{ &(*table).entries[va.vpn(level)] } ;
+            if !v.is_valid(){
+                break level_size(level) ;
+            }
+            if v.is_leaf() || level == 0{
+                return Some(va) ;
+            }
+            table = next_table(v) ;
+            level -= 1 ;
+        } ;
+        match va.align_down(hole).checked_add(hole){
+            Some(next) => va = next ,
+            None => break ,
+        }
+    }
+    None
+}
+
+// Bytes one entry maps at this level: 4 KiB , 2 MiB , 1 GiB , 512 GiB , 256 TiB
+pub const fn level_size(level: usize) -> usize{
+    1 << (12 + 9 * level)
+}
+
+// The next level table a valid non-leaf entry points to
+fn next_table(v: &Entry) -> *mut Table{
+    v.addr().as_ptr()
+}
+
+impl Table{
+    pub fn is_empty(&self) -> bool{
+        self.entries.iter().all(|e| !e.is_valid())
+    }
+}
+
+// A zeroed page for a table. The shrinkers get a go before we give up and return null.
+fn alloc_table() -> *mut Table{
+    oom::retry(PAGE_SIZE , || zero_alloc_internal(1 , PAGE_ORDER)) as *mut Table
+}
+
+// Turn the large leaf v on level (1 or up) into a pointer to a new table of 512 leaves
+// one level down that map the same range with the same flags. False if we're out of pages.
+fn split_leaf(v: &mut Entry , level: usize) -> bool{
+    let table = alloc_table() ;
+    if table.is_null(){
+        return false ;
+    }
+    let flags = v.flags() ;
+    let base = v.ppn() ;
+    let step = level_size(level - 1) >> 12 ;  // One child's worth of pages
+    unsafe{
+        for (i , e) in (*table).entries.iter_mut().enumerate(){
+            e.set(base + i * step , flags) ;
+        }
+    }
+    v.set(PhysAddr::from_ptr(table).page_num() , PteFlags::VALID) ;
+    true
+}
+
+// Drop the TLB entries for va on this hart. Needed after changing a table the hart may be using.
+pub fn flush_tlb(va: VirtAddr){
+    #[cfg(target_arch = "riscv64")]
+    unsafe{
+        core::arch::asm!("sfence.vma {0}, zero" , in(reg) va.as_usize()) ;
+    }
+    #[cfg(not(target_arch = "riscv64"))]
+    let _ = va ;
+}
+
+// Why leaf_within didn't get to a leaf
+enum Miss{
+    Invalid(usize) ,  // The entry on this level isn't valid
+    NoPage ,          // A large leaf had to be split and there was no page for the table
+}
+
+// Walk down to the leaf that maps va. A large leaf reaching outside [start , end) is split on the
+// way, so the leaf we end up at lies inside. tables[i] is left at the table we looked at on level i.
+// Ok with the level of the leaf. The leaves split before a Miss::NoPage map what they did before.
+fn leaf_within(mode: PagingMode , root: &mut Table , va: VirtAddr , start: VirtAddr , end: VirtAddr ,
+               tables: &mut [*mut Table ; MAX_LEVELS]) -> Result<usize , Miss>{
+    tables[mode.top()] = root as *mut Table ;
+
+    let mut level = mode.top() ;
+    unsafe{
+        loop{
+            let v = &mut (*tables[level]).entries[va.vpn(level)] ;
+            if !v.is_valid(){
+                return Err(Miss::Invalid(level)) ;
+            }
+            let leaf_start = va.align_down(level_size(level)) ;
+            if v.is_leaf() && level > 0 && (leaf_start < start || leaf_start + level_size(level) > end) && !split_leaf(v , level){
+                return Err(Miss::NoPage) ;
+            }
+            if v.is_leaf(){
+                return Ok(level) ;
+            }
+            if level == 0{
+                // A pointer entry on the last level, nothing we made
+                return Err(Miss::Invalid(0)) ;
+            }
+            tables[level - 1] = next_table(v) ;
+            level -= 1 ;
+        }
+    }
+}
+
+// Clear the leaf that maps va and free the tables below the root that end up empty.
+// A large leaf reaching outside [start , end) is split first, so only what is inside goes.
+// Ok with the leaf that was cleared, or the Miss that stopped us (nothing is cleared then).
+fn unmap_leaf(mode: PagingMode , root: &mut Table , va: VirtAddr , start: VirtAddr , end: VirtAddr) -> Result<MapRange , Miss>{
+    let top = mode.top() ;
+    let mut tables: [*mut Table ; MAX_LEVELS] = [null_mut() ; MAX_LEVELS] ;
+    let level = leaf_within(mode , root , va , start , end , &mut tables)? ;
+    unsafe{
+        let v = &mut (*tables[level]).entries[va.vpn(level)] ;
+        let leaf = MapRange{ va: va.align_down(level_size(level)) , pa: v.addr() , size: level_size(level) , flags: v.flags() , level } ;
+        v.clear() ;
+        flush_tlb(va) ;
+        // Walk back up, a table that lost its last entry goes back to the page allocator
+        for i in level..top{
+            if !(*tables[i]).is_empty(){
+                break ;
+            }
+            dealloc(tables[i] as *mut u8) ;
+            (*tables[i + 1]).entries[va.vpn(i + 1)].clear() ;
+        }
+        Ok(leaf)
+    }
+}
+
+// Remove the mapping of the 4 KiB page holding va (a large leaf is split up for it).
+// Returns the physical address va was mapped to, None if nothing was.
+// The page itself isn't ours to free, but tables left empty are freed.
+// Err if va is inside a large leaf and there is no page to split it, nothing is unmapped then.
+pub fn unmap(root: &mut Table , va: VirtAddr) -> Result<Option<PhysAddr> , AllocFailure>{
+    let page = va.align_down(PAGE_SIZE) ;
+    match unmap_leaf(paging_mode() , root , va , page , page + PAGE_SIZE){
+        Ok(r) => Ok(Some(r.pa + (va - r.va))) ,
+        Err(Miss::Invalid(_)) => Ok(None) ,
+        Err(Miss::NoPage) => Err(AllocFailure::OutOfMemory{ size: PAGE_SIZE , align: PAGE_SIZE }) ,
+    }
+}
+
+// Remove every mapping in [start , end). Holes are skipped a whole table at a time.
+// Err if a large leaf at either end needs splitting and there is no page for it, nothing is
+// unmapped then.
+pub fn unmap_range(root: &mut Table , start: VirtAddr , end: VirtAddr) -> Result<() , AllocFailure>{
+    unmap_range_in(paging_mode() , root , start , end , |_| {})
+}
+
+// unmap_range that calls f with every leaf it clears, after the TLB has forgotten it.
+// That is the time to free the pages behind it.
+pub fn unmap_range_with<F: FnMut(&MapRange)>(root: &mut Table , start: VirtAddr , end: VirtAddr , f: F) -> Result<() , AllocFailure>{
+    unmap_range_in(paging_mode() , root , start , end , f)
+}
+
+fn unmap_range_in<F: FnMut(&MapRange)>(mode: PagingMode , root: &mut Table , start: VirtAddr , end: VirtAddr , mut f: F) -> Result<() , AllocFailure>{
+    let start = start.align_down(PAGE_SIZE) ;
+    let end = end.align_up(PAGE_SIZE) ;
+    if start >= end{
+        return Ok(()) ;
+    }
+
+    // Like protect, only the leaves at the two ends can reach outside the range. Split them
+    // before clearing anything, so running out of pages leaves every mapping in place.
+    let mut tables: [*mut Table ; MAX_LEVELS] = [null_mut() ; MAX_LEVELS] ;
+    for edge in [start , VirtAddr::new(end.as_usize() - PAGE_SIZE)]{
+        if let Err(Miss::NoPage) = leaf_within(mode , root , edge , start , end , &mut tables){
+            return Err(AllocFailure::OutOfMemory{ size: PAGE_SIZE , align: PAGE_SIZE }) ;
+        }
+    }
+
+    let mut va = start ;
+    while va < end{
+        let level = match unmap_leaf(mode , root , va , start , end){
+            Ok(leaf) => {
+                f(&leaf) ;
+                leaf.level
+            }
+            Err(Miss::Invalid(level)) => level ,
+            Err(Miss::NoPage) => unreachable!("unmap: the ends were split already") ,
+        } ;
+        let next = va.align_down(level_size(level)).checked_add(level_size(level)) ;
+        match next{
+            Some(next) => va = next ,
+            None => break ,
+        }
+    }
+    Ok(())
+}
+
+// Why protect changed nothing
+#[derive(Copy , Clone , Debug , PartialEq , Eq)]
+pub enum ProtectError{
+    NotMapped(VirtAddr) ,  // The first address in range that isn't mapped
+    OutOfMemory ,          // No page to split a large leaf the range covers part of
+}
+
+// Give the pages in range the R , W , X and U bits of flags, the rest of each leaf (A , D , G and
+// the software bits) stays as it was. A large leaf the range covers part of is split, one it covers
+// whole keeps its size. On Err no permission has changed (a split may have, it maps the same).
+// The hart's TLB forgets the old permissions, other harts need their own sfence.vma.
+pub fn protect(root: &mut Table , range: Range<VirtAddr> , flags: PteFlags) -> Result<() , ProtectError>{
+    protect_in(paging_mode() , root , range , flags)
+}
+
+fn protect_in(mode: PagingMode , root: &mut Table , range: Range<VirtAddr> , flags: PteFlags) -> Result<() , ProtectError>{
+    assert!(flags.is_leaf() , "protect: {:?} has none of RWX" , flags) ;
+    // W without R is reserved for future use, the hardware faults on it
+    assert!(!flags.contains(PteFlags::WRITE) || flags.contains(PteFlags::READ) , "protect: {:?} is writable but not readable" , flags) ;
+    let start = range.start.align_down(PAGE_SIZE) ;
+    let end = range.end.align_up(PAGE_SIZE) ;
+    if start >= end{
+        return Ok(()) ;
+    }
+
+    // All of it has to be mapped before we touch any of it
+    let mut va = start ;
+    while va < end{
+        let leaf = lookup_in(mode , root , va).ok_or(ProtectError::NotMapped(va))? ;
+        match leaf.va.checked_add(leaf.size){
+            Some(next) => va = next ,
+            None => break ,
+        }
+    }
+
+    // Only the leaves at the two ends can reach outside the range. Split them before we change
+    // anything, so running out of pages for that leaves the permissions as they were.
+    let mut tables: [*mut Table ; MAX_LEVELS] = [null_mut() ; MAX_LEVELS] ;
+    for edge in [start , VirtAddr::new(end.as_usize() - PAGE_SIZE)]{
+        match leaf_within(mode , root , edge , start , end , &mut tables){
+            Ok(_) => {}
+            Err(Miss::Invalid(_)) => return Err(ProtectError::NotMapped(edge)) ,
+            Err(Miss::NoPage) => return Err(ProtectError::OutOfMemory) ,
+        }
+    }
+
+    let perms = (PteFlags::RWX | PteFlags::USER).bits() ;
+    let mut va = start ;
+    while va < end{
+        let level = leaf_within(mode , root , va , start , end , &mut tables).map_err(|_| ProtectError::NotMapped(va))? ;
+        let v = unsafe{ &mut (*tables[level]).entries[va.vpn(level)] } ;
+        v.set_entry((v.get_entry() & !perms) | (flags.bits() & perms)) ;
+        flush_tlb(va) ;
+        match va.align_down(level_size(level)).checked_add(level_size(level)){
+            Some(next) => va = next ,
+            None => break ,
+        }
+    }
+    Ok(())
+}
+
+// Free every table below root, on all levels, and clear root. The pages the leaves
+// point to aren't touched, root itself stays with the caller. Returns the tables freed.
+pub fn unmap_all(root: &mut Table) -> usize{
+    unmap_all_in(paging_mode() , root)
+}
+
+fn unmap_all_in(mode: PagingMode , root: &mut Table) -> usize{
+    let mut freed = 0 ;
+    for v in root.entries.iter_mut(){
+        if v.is_valid() && !v.is_leaf(){
+            freed += free_table(next_table(v) , mode.top() - 1) ;
+        }
+        v.clear() ;
+    }
+    #[cfg(target_arch = "riscv64")]
+    unsafe{
+        core::arch::asm!("sfence.vma") ;
+    }
+    freed
+}
+
+// Free the table at level and everything below it, returns the number of tables freed
+fn free_table(table: *mut Table , level: usize) -> usize{
+    let mut freed = 1 ;
+    unsafe{
+        if level > 0{
+            for v in (*table).entries.iter(){
+                if v.is_valid() && !v.is_leaf(){
+                    freed += free_table(next_table(v) , level - 1) ;
+                }
+            }
+        }
+    }
+    dealloc(table as *mut u8) ;
+    freed
+}
+
+// Tables reachable from table on level, not counting table itself
+fn count_tables(table: &Table , level: usize) -> usize{
+    let mut n = 0 ;
+    for v in table.entries.iter(){
+        if v.is_valid() && !v.is_leaf() && level > 0{
+            n += 1 + count_tables(unsafe{ &*next_table(v) } , level - 1) ;
+        }
+    }
+    n
+}
+
+// Pages the tree under root takes up, root included
+pub fn table_pages(root: &Table) -> usize{
+    1 + count_tables(root , paging_mode().top())
+}
+
+// A run of leaves on the same level with the same flags, contiguous in va and in pa
+#[derive(Copy , Clone , Debug , PartialEq , Eq)]
+pub struct MapRange{
+    pub va: VirtAddr ,
+    pub pa: PhysAddr ,
+    pub size: usize ,  // Bytes
+    pub flags: PteFlags ,
+    pub level: usize ,
+}
+
+// va => last va -> pa => last pa [RWXUGAD] (level), ends inclusive like print_allocations
+impl fmt::Display for MapRange{
+    fn fmt(&self , f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f , "{:#x} => {:#x} -> {:#x} => {:#x} [{}] ({})" ,
+               self.va , self.va + (self.size - 1) , self.pa , self.pa + (self.size - 1) , self.flags , self.level)
+    }
+}
+
+// Call f for every leaf under table, in table order. base has the va bits of the levels above.
+fn for_each_leaf<F: FnMut(VirtAddr , &Entry , usize)>(mode: PagingMode , table: &Table , level: usize , base: usize , f: &mut F){
+    for (i , v) in table.entries.iter().enumerate(){
+        if !v.is_valid(){
+            continue ;
+        }
+        let va = base | (i << (12 + 9 * level)) ;
+        if v.is_leaf(){
+            // Addresses in the upper half have the top bit copied up through bit 63
+            let bits = 12 + 9 * mode.levels() ;
+            let va = if (va >> (bits - 1)) & 1 != 0 { va | !((1 << bits) - 1) } else { va } ;
+            f(VirtAddr::new(va) , v , level) ;
+        }
+        else if level > 0{
+            for_each_leaf(mode , unsafe{ &*next_table(v) } , level - 1 , va , f) ;
+        }
+    }
+}
+
+// Call f for every mapped range under root, lowest va first, with neighbouring leaves merged
+pub fn for_each_range<F: FnMut(&MapRange)>(root: &Table , f: F){
+    for_each_range_in(paging_mode() , root , f)
+}
+
+fn for_each_range_in<F: FnMut(&MapRange)>(mode: PagingMode , root: &Table , mut f: F){
+    let mut run: Option<MapRange> = None ;
+    for_each_leaf(mode , root , mode.top() , 0 , &mut |va , v , level| {
+        let size = level_size(level) ;
+        if let Some(r) = run.as_mut(){
+            if r.flags == v.flags() && r.level == level
+                && r.va.checked_add(r.size) == Some(va) && r.pa + r.size == v.addr(){
+                r.size += size ;
+                return ;
+            }
+            f(r) ;
+        }
+        run = Some(MapRange{ va , pa: v.addr() , size , flags: v.flags() , level }) ;
+    }) ;
+    if let Some(r) = run{
+        f(&r) ;
+    }
+}
+
+// Print what root maps and how many pages its tables take
+pub fn print_table(root: &Table){
+    let mode = paging_mode() ;
+    let mut ranges = 0 ;
+    println!() ;
+    println!("PAGE TABLE {:#x} ({:?})" , root as *const Table as usize , mode) ;
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~") ;
+    for_each_range_in(mode , root , |r| {
+        println!("{}" , r) ;
+        ranges += 1 ;
+    }) ;
+    let tables = 1 + count_tables(root , mode.top()) ;
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~") ;
+    println!("{} ranges in {} table pages ({} bytes)." , ranges , tables , tables * PAGE_SIZE) ;
+    println!() ;
+}
+
+// The page table code takes its tables from the kernel's page heap, give that one a buffer
+// for the host tests (once, all tests share it). The tests that use it run one at a time,
+// as long as they hold on to what this returns, so they can count its free pages.
+#[cfg(test)]
+pub(crate) fn init_test_heap() -> std::sync::MutexGuard<'static , ()>{
+    static INIT: std::sync::Once = std::sync::Once::new() ;
+    static USERS: std::sync::Mutex<()> = std::sync::Mutex::new(()) ;
+    INIT.call_once(|| {
+        let region = vec![0u8 ; 2048 * PAGE_SIZE].leak() ;
+        *PAGE_HEAP.lock() = PageHeap::from_slice(region) ;
+    }) ;
+    // A test that failed while holding it left the heap no worse than any other failed test
+    USERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::* ;
+    use crate::test_util::{Rng , dtb_blob} ;
+
+    // A heap over a leaked buffer of roughly "pages" pages
+    fn heap(pages: usize) -> PageHeap{
+        let region = vec![0u8 ; (pages + 2) * PAGE_SIZE].leak() ;
+        PageHeap::from_slice(region)
+    }
+
+    // Stamp every page of a run so overlapping runs show up when we check them
+    fn stamp(ptr: *mut u8 , pages: usize , tag: u64){
+        for i in 0..pages{
+            unsafe{ *(ptr.add(i * PAGE_SIZE + 64) as *mut u64) = tag ; }
+        }
+    }
+
+    fn check_stamp(ptr: *mut u8 , pages: usize , tag: u64){
+        for i in 0..pages{
+            assert_eq!(unsafe{ *(ptr.add(i * PAGE_SIZE + 64) as *const u64) } , tag) ;
+        }
+    }
+
+    #[test]
+    fn alloc_and_dealloc(){
+        let mut h = heap(64) ;
+        let total = h.stats().total ;
+        let a = h.alloc(3) ;
+        let b = h.zero_alloc(5) ;
+        assert!(!a.is_null() && !b.is_null()) ;
+        assert_eq!(a as usize % PAGE_SIZE , 0) ;
+        assert_eq!(h.stats().used , 8) ;
+        h.dealloc(a) ;
+        h.dealloc(b) ;
+        let s = h.stats() ;
+        assert_eq!((s.used , s.free , s.largest_free_run) , (0 , total , total)) ;
+    }
+
+    #[test]
+    fn exhaustion_returns_null(){
+        let mut h = heap(32) ;
+        let total = h.stats().total ;
+        let mut count = 0 ;
+        while !h.alloc(1).is_null(){
+            count += 1 ;
+        }
+        assert_eq!(count , total) ;
+        assert_eq!(h.stats().free , 0) ;
+        assert!(h.alloc(total + 1).is_null()) ;
+    }
+
+    #[test]
+    fn frees_coalesce_back_into_one_run(){
+        let mut h = heap(256) ;
+        let total = h.stats().total ;
+        let mut rng = Rng(0x1234_5678) ;
+        let mut live = Vec::new() ;
+        loop{
+            let p = h.alloc(1 + rng.next() % 4) ;
+            if p.is_null(){
+                break ;
+            }
+            live.push(p) ;
+        }
+        while !live.is_empty(){
+            let p = live.swap_remove(rng.next() % live.len()) ;
+            h.dealloc(p) ;
+        }
+        assert_eq!(h.stats().largest_free_run , total) ;
+        // The biggest naturally aligned block must be whole again
+        let big = 1 << (usize::BITS - 1 - total.leading_zeros()) ;
+        let p = h.alloc_aligned(big / 2 , PAGE_ORDER + order_for(big / 2)) ;
+        assert!(!p.is_null()) ;
+    }
+
+    #[test]
+    fn aligned_allocations(){
+        let mut h = heap(512) ;
+        let pad = h.alloc(1) ;
+        for order in [PAGE_ORDER , 13 , 16 , 18]{
+            let p = h.alloc_aligned(3 , order) ;
+            assert!(!p.is_null()) ;
+            assert_eq!(p as usize % (1 << order) , 0) ;
+            h.dealloc(p) ;
+        }
+        h.dealloc(pad) ;
+        assert_eq!(h.stats().used , 0) ;
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_panics(){
+        let mut h = heap(16) ;
+        let p = h.alloc(2) ;
+        h.dealloc(p) ;
+        h.dealloc(p) ;
+    }
+
+    #[test]
+    #[should_panic(expected = "middle of an allocation")]
+    fn interior_pointer_panics(){
+        let mut h = heap(16) ;
+        let p = h.alloc(4) ;
+        h.dealloc(unsafe{ p.add(PAGE_SIZE) }) ;
+    }
+
+    #[test]
+    fn reference_counts(){
+        let mut h = heap(16) ;
+        let p = h.alloc(1) ;
+        h.get(p) ;
+        assert_eq!(h.ref_count(p) , 2) ;
+        assert!(!h.put(p)) ;
+        assert_eq!(h.stats().used , 1) ;
+        assert!(h.put(p)) ;
+        assert_eq!(h.stats().used , 0) ;
+    }
+
+    #[test]
+    fn reserved_pages_are_never_handed_out(){
+        let mut h = heap(64) ;
+        let start = h.get_alloc_start() + 5 * PAGE_SIZE ;
+        let end = start + 3 * PAGE_SIZE - 100 ;
+        assert!(h.reserve(start , end)) ;
+        assert!(h.is_reserved(start) && h.is_reserved(start + 2 * PAGE_SIZE)) ;
+        assert!(!h.is_reserved(start + 3 * PAGE_SIZE)) ;
+        loop{
+            let p = h.alloc(1) as usize ;
+            if p == 0{
+                break ;
+            }
+            assert!(p < start || p >= start + 3 * PAGE_SIZE) ;
+        }
+        let s = h.stats() ;
+        assert_eq!((s.reserved , s.free) , (3 , 0)) ;
+
+        // With the list full a range in the heap is refused, its pages stay free
+        let mut h = heap(64) ;
+        let start = h.get_alloc_start() ;
+        for i in 0..MAX_RESERVED{
+            assert!(h.reserve(start + i * PAGE_SIZE , start + (i + 1) * PAGE_SIZE)) ;
+        }
+        let last = start + 40 * PAGE_SIZE ;
+        assert!(!h.reserve(last , last + PAGE_SIZE)) ;
+        assert!(!h.is_reserved(last)) ;
+        assert_eq!(h.stats().reserved , MAX_RESERVED) ;
+    }
+
+    #[test]
+    fn dtb_at_heap_start_survives_init(){
+        let region = vec![0u8 ; 66 * PAGE_SIZE].leak() ;
+        let start = align_val(region.as_ptr() as usize , PAGE_ORDER) ;
+        // RAM ends before the buffer does, the heap must stop there
+        let mem = (start , 48 * PAGE_SIZE) ;
+        let rsv = (start + 20 * PAGE_SIZE , 2 * PAGE_SIZE) ;
+        let blob = dtb_blob(mem , rsv) ;
+        unsafe{
+            core::ptr::copy_nonoverlapping(blob.as_ptr() , start as *mut u8 , blob.len()) ;
+        }
+
+        let mut h = PageHeap::empty() ;
+        unsafe{
+            h.init_with_fdt(start , start + 64 * PAGE_SIZE , start) ;
+        }
+        let now = unsafe{ core::slice::from_raw_parts(start as *const u8 , blob.len()) } ;
+        assert_eq!(now , &blob[..]) ;
+        assert!(h.get_desc_start() >= start + blob.len()) ;
+        assert_eq!(h.get_alloc_end() , start + 48 * PAGE_SIZE) ;
+        assert!(h.is_reserved(start) && h.is_reserved(rsv.0 + PAGE_SIZE)) ;
+        loop{
+            let p = h.alloc(1) as usize ;
+            if p == 0{
+                break ;
+            }
+            assert!(p >= h.get_alloc_start() && p < h.get_alloc_end()) ;
+            assert!(p < rsv.0 || p >= rsv.0 + rsv.1) ;
+        }
+        assert_eq!(h.stats().reserved , 2) ;
+    }
+
+    #[test]
+    fn random_alloc_free_sequences(){
+        for seed in 1..20u64{
+            let mut h = heap(300) ;
+            let total = h.stats().total ;
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15)) ;
+            let mut live: Vec<(*mut u8 , usize , u64)> = Vec::new() ;
+            for step in 0..2000u64{
+                if live.is_empty() || !rng.next().is_multiple_of(3){
+                    let pages = 1 + rng.next() % 9 ;
+                    let p = h.alloc(pages) ;
+                    if !p.is_null(){
+                        stamp(p , pages , step) ;
+                        live.push((p , pages , step)) ;
+                    }
+                }
+                else{
+                    let (p , pages , tag) = live.swap_remove(rng.next() % live.len()) ;
+                    check_stamp(p , pages , tag) ;
+                    h.dealloc(p) ;
+                }
+                let used: usize = live.iter().map(|l| l.1).sum() ;
+                assert_eq!(h.stats().used , used) ;
+            }
+            for (p , pages , tag) in live{
+                check_stamp(p , pages , tag) ;
+                h.dealloc(p) ;
+            }
+            assert_eq!(h.stats().largest_free_run , total) ;
+        }
+    }
+
+    #[test]
+    fn address_and_flag_types(){
+        let a = pa(0x8020_1234) ;
+        assert_eq!(a.align_down(PAGE_SIZE) , pa(0x8020_1000)) ;
+        assert_eq!(a.align_up(level_size(1)) , pa(0x8040_0000)) ;
+        assert!(pa(0x8020_0000).is_aligned(level_size(1)) && !a.is_aligned(PAGE_SIZE)) ;
+        assert_eq!((a.page_offset() , a.page_num() , a.page_num().addr()) , (0x234 , PageNum::new(0x80201) , pa(0x8020_1000))) ;
+        assert_eq!(va(0x7f_c060_1000).vpn(2) , 0x1ff) ;
+        assert_eq!(va(0x7f_c060_1000).vpn(1) , 3) ;
+        assert_eq!(va(0xffff_ffff_ffff_f000).checked_add(PAGE_SIZE) , None) ;
+        assert_eq!(format!("{:?}" , a) , "PhysAddr(0x80201234)") ;
+
+        let f = PteFlags::RW | PteFlags::VALID ;
+        assert!(f.contains(PteFlags::READ) && !f.contains(PteFlags::RWX) && f.intersects(PteFlags::RX)) ;
+        assert!(f.is_leaf() && !PteFlags::VALID.is_leaf()) ;
+        assert_eq!((f & PteFlags::WRITE , f.difference(PteFlags::WRITE)) , (PteFlags::WRITE , PteFlags::READ | PteFlags::VALID)) ;
+        assert_eq!(PteFlags::from_bits(0x400) , None) ;
+        assert_eq!((!PteFlags::empty()).bits() , 0x3ff) ;
+        assert_eq!(format!("{:?}" , f) , "PteFlags(VALID | READ | WRITE)") ;
+
+        // Flags and PPN land in their own bits of the raw entry
+        let mut e = Entry{ entry: 0 } ;
+        e.set(a.page_num() , f | PteFlags::DIRTY) ;
+        assert_eq!(e.get_entry() , (0x80201 << 10) | 0x87) ;
+        assert_eq!((e.addr() , e.flags()) , (pa(0x8020_1000) , f | PteFlags::DIRTY)) ;
+        assert!(e.is_valid() && e.is_leaf()) ;
+        e.clear() ;
+        assert!(!e.is_valid()) ;
+    }
+
+    // A root table on the shared test heap, which is ours until the guard goes
+    fn kernel_root() -> (std::sync::MutexGuard<'static , ()> , &'static mut Table){
+        let guard = init_test_heap() ;
+        (guard , unsafe{ &mut *(zero_alloc(1) as *mut Table) })
+    }
+
+    const RW: PteFlags = PteFlags::RW ;
+
+    fn va(addr: usize) -> VirtAddr{
+        VirtAddr::new(addr)
+    }
+
+    fn pa(addr: usize) -> PhysAddr{
+        PhysAddr::new(addr)
+    }
+
+    #[test]
+    fn unmap_frees_empty_tables(){
+        let (_heap , root) = kernel_root() ;
+        mapping(root , va(0x4000_0000) , pa(0x8000_0000) , RW , 0) ;
+        mapping(root , va(0x4000_1000) , pa(0x8000_5000) , RW , 0) ;
+        mapping(root , va(0x4020_0000) , pa(0x8000_9000) , RW , 0) ;
+        assert_eq!(count_tables(root , 2) , 3) ;
+
+        assert_eq!(unmap(root , va(0x4000_1234)) , Ok(Some(pa(0x8000_5234)))) ;
+        assert_eq!(translate(root , va(0x4000_1000)) , None) ;
+        assert_eq!(translate(root , va(0x4000_0010)) , Some(pa(0x8000_0010))) ;
+        assert_eq!(unmap(root , va(0x4000_1000)) , Ok(None)) ;
+
+        // Last page of the first level 0 table, it goes and the level 1 table stays
+        unmap(root , va(0x4000_0000)).unwrap() ;
+        assert_eq!(count_tables(root , 2) , 2) ;
+        unmap(root , va(0x4020_0000)).unwrap() ;
+        assert!(root.is_empty()) ;
+        dealloc(root as *mut Table as *mut u8) ;
+    }
+
+    #[test]
+    fn unmap_range_skips_holes(){
+        let (_heap , root) = kernel_root() ;
+        for i in 0..600{
+            mapping(root , va(0x1000_0000 + i * PAGE_SIZE) , pa(0x8000_0000 + i * PAGE_SIZE) , RW , 0) ;
+        }
+        mapping(root , va(0x40_0000_0000 - PAGE_SIZE) , pa(0x8000_0000) , RW , 0) ;
+        // Keeps the first and the last page, walks 256 GiB of mostly nothing
+        unmap_range(root , va(0x1000_1000) , va(0x40_0000_0000 - PAGE_SIZE)).unwrap() ;
+        assert_eq!(translate(root , va(0x1000_0000)) , Some(pa(0x8000_0000))) ;
+        assert_eq!(translate(root , va(0x1000_1000)) , None) ;
+        assert_eq!(translate(root , va(0x1025_7000)) , None) ;
+        assert!(translate(root , va(0x40_0000_0000 - PAGE_SIZE)).is_some()) ;
+        assert_eq!(count_tables(root , 2) , 4) ;
+
+        assert_eq!(unmap_all(root) , 4) ;
+        assert!(root.is_empty()) ;
+        assert_eq!(translate(root , va(0x1000_0000)) , None) ;
+        dealloc(root as *mut Table as *mut u8) ;
+    }
+
+    // Leaves on each level, (4 KiB , 2 MiB , 1 GiB)
+    fn count_leaves(table: &Table , level: usize , counts: &mut [usize ; 3]){
+        for v in table.entries.iter(){
+            if v.is_valid() && v.is_leaf(){
+                counts[level] += 1 ;
+            }
+            else if v.is_valid() && level > 0{
+                count_leaves(unsafe{ &*next_table(v) } , level - 1 , counts) ;
+            }
+        }
+    }
+
+    #[test]
+    fn large_leaves_translate_split_and_unmap(){
+        let (_heap , root) = kernel_root() ;
+        mapping(root , va(0x4000_0000) , pa(0x8000_0000) , RW , 2) ;
+        mapping(root , va(0x20_0000) , pa(0x8060_0000) , RW , 1) ;
+        assert_eq!(translate(root , va(0x5234_5678)) , Some(pa(0x9234_5678))) ;
+        assert_eq!(translate(root , va(0x3f_fff8)) , Some(pa(0x807f_fff8))) ;
+
+        // A page mapped into the megapage splits it, the rest keeps its old translation
+        mapping(root , va(0x21_0000) , pa(0x9000_0000) , RW , 0) ;
+        assert_eq!(translate(root , va(0x21_0010)) , Some(pa(0x9000_0010))) ;
+        assert_eq!(translate(root , va(0x22_0010)) , Some(pa(0x8062_0010))) ;
+
+        // Unmapping a page of the gigapage leaves the other 1 GiB - 4 KiB mapped
+        assert_eq!(unmap(root , va(0x4010_0000)) , Ok(Some(pa(0x8010_0000)))) ;
+        assert_eq!(translate(root , va(0x4010_0000)) , None) ;
+        assert_eq!(translate(root , va(0x4010_1000)) , Some(pa(0x8010_1000))) ;
+        let mut counts = [0 ; 3] ;
+        count_leaves(root , 2 , &mut counts) ;
+        assert_eq!(counts , [512 + 511 , 511 , 0]) ;
+
+        // A range that covers whole megapages takes them without splitting
+        unmap_range(root , va(0x4020_0000) , va(0x8000_0000)).unwrap() ;
+        assert_eq!(translate(root , va(0x4020_0000)) , None) ;
+        assert_eq!(translate(root , va(0x401f_f000)) , Some(pa(0x801f_f000))) ;
+        unmap_all(root) ;
+        dealloc(root as *mut Table as *mut u8) ;
+    }
+
+    #[test]
+    fn large_leaf_wont_replace_a_table_in_use(){
+        let (_heap , root) = kernel_root() ;
+        mapping(root , va(0x4000_1000) , pa(0x8000_5000) , RW , 0) ;
+        for level in [1 , 2]{
+            assert_eq!(try_mapping(root , va(0x4000_0000) , pa(0x8000_0000) , RW , level) , Err(MapError::Mapped(va(0x4000_0000)))) ;
+        }
+        assert_eq!(translate(root , va(0x4000_1000)) , Some(pa(0x8000_5000))) ;
+        assert_eq!(count_tables(root , 2) , 2) ;
+
+        // Once the page is gone so are its tables, and the megapage goes in
+        unmap(root , va(0x4000_1000)).unwrap() ;
+        try_mapping(root , va(0x4000_0000) , pa(0x8000_0000) , RW , 1).unwrap() ;
+        assert_eq!(translate(root , va(0x4000_1000)) , Some(pa(0x8000_1000))) ;
+        unmap_all(root) ;
+        dealloc(root as *mut Table as *mut u8) ;
+    }
+
+    #[test]
+    fn first_mapped_looks_only_at_the_range(){
+        let (_heap , root) = kernel_root() ;
+        mapping(root , va(0x20_0000) , pa(0x8020_0000) , RW , 1) ;
+        mapping(root , va(0x4000_3000) , pa(0x8000_0000) , RW , 0) ;
+        assert_eq!(first_mapped(root , va(0) , va(0x20_0000)) , None) ;
+        assert_eq!(first_mapped(root , va(0x1000) , va(0x30_0000)) , Some(va(0x20_0000))) ;
+        assert_eq!(first_mapped(root , va(0x30_0000) , va(0x4000_3000)) , Some(va(0x30_0000))) ;
+        assert_eq!(first_mapped(root , va(0x40_0000) , va(0x4000_3000)) , None) ;
+        assert_eq!(first_mapped(root , va(0x40_0000) , va(0x40_0000_0000)) , Some(va(0x4000_3000))) ;
+        unmap_all(root) ;
+        dealloc(root as *mut Table as *mut u8) ;
+    }
+
+    #[test]
+    fn unmap_without_a_page_to_split_changes_nothing(){
+        let (_heap , root) = kernel_root() ;
+        mapping(root , va(0x20_0000) , pa(0x8020_0000) , RW , 1) ;
+        mapping(root , va(0x40_0000) , pa(0x8040_0000) , RW , 1) ;
+        let mut hog = Vec::new() ;
+        loop{
+            let p = alloc(1) ;
+            if p.is_null(){
+                break ;
+            }
+            hog.push(p) ;
+        }
+        // Both end inside a megapage, which would need a new table
+        assert!(unmap_range(root , va(0x20_0000) , va(0x50_0000)).is_err()) ;
+        assert!(unmap(root , va(0x21_0000)).is_err()) ;
+        for p in hog{
+            dealloc(p) ;
+        }
+        let mut counts = [0 ; 3] ;
+        count_leaves(root , 2 , &mut counts) ;
+        assert_eq!(counts , [0 , 2 , 0]) ;
+        assert_eq!(translate(root , va(0x20_0010)) , Some(pa(0x8020_0010))) ;
+
+        // With pages to split the second megapage the first goes whole
+        unmap_range(root , va(0x20_0000) , va(0x50_0000)).unwrap() ;
+        assert_eq!(translate(root , va(0x4f_f000)) , None) ;
+        assert_eq!(translate(root , va(0x50_0000)) , Some(pa(0x8050_0000))) ;
+        unmap_all(root) ;
+        dealloc(root as *mut Table as *mut u8) ;
+    }
+
+    #[test]
+    fn id_map_range_uses_large_leaves(){
+        let (_heap , root) = kernel_root() ;
+        // 4 KiB up to the first 2 MiB boundary, then megapages, a gigapage, megapages and 4 KiB again
+        let start = 0x3fe0_0000 - 3 * PAGE_SIZE ;
+        let end = 0x8040_0000 + 5 * PAGE_SIZE ;
+        crate::id_map_range(root , pa(start) , pa(end) , RW) ;
+        let mut counts = [0 ; 3] ;
+        count_leaves(root , 2 , &mut counts) ;
+        assert_eq!(counts , [3 + 5 , 1 + 2 , 1]) ;
+        for addr in [start , 0x3fe0_0000 , 0x4000_0000 , 0x7fff_f000 , 0x8000_0000 , end - 1]{
+            assert_eq!(translate(root , va(addr)) , Some(pa(addr))) ;
+        }
+        assert_eq!(translate(root , va(end)) , None) ;
+        assert_eq!(translate(root , va(start - 1)) , None) ;
+        unmap_all(root) ;
+        dealloc(root as *mut Table as *mut u8) ;
+    }
+
+    #[test]
+    fn dump_merges_neighbouring_leaves(){
+        let (_heap , root) = kernel_root() ;
+        let mode = PagingMode::Sv39 ;
+        for i in 0..3{
+            mapping_in(mode , root , va(0x1000_0000 + i * PAGE_SIZE) , pa(0x8000_0000 + i * PAGE_SIZE) , RW , 0) ;
+        }
+        // Next va, but not the next pa, then the next of both with other flags
+        mapping_in(mode , root , va(0x1000_3000) , pa(0x9000_0000) , RW , 0) ;
+        mapping_in(mode , root , va(0x1000_4000) , pa(0x9000_1000) , PteFlags::RX , 0) ;
+        mapping_in(mode , root , va(0x4000_0000) , pa(0x8000_0000) , RW , 1) ;
+        mapping_in(mode , root , va(0x4020_0000) , pa(0x8020_0000) , RW , 1) ;
+        // Root entry 256, the first one of the upper half
+        mapping_in(mode , root , va(0x40_0000_0000) , pa(0) , RW | PteFlags::GLOBAL , 2) ;
+
+        let mut ranges = Vec::new() ;
+        for_each_range_in(mode , root , |r| ranges.push((r.va.as_usize() , r.pa.as_usize() , r.size , r.level))) ;
+        assert_eq!(ranges , vec![
+            (0x1000_0000 , 0x8000_0000 , 3 * PAGE_SIZE , 0) ,
+            (0x1000_3000 , 0x9000_0000 , PAGE_SIZE , 0) ,
+            (0x1000_4000 , 0x9000_1000 , PAGE_SIZE , 0) ,
+            (0x4000_0000 , 0x8000_0000 , 2 * level_size(1) , 1) ,
+            (0xffff_ffc0_0000_0000 , 0 , level_size(2) , 2) ,
+        ]) ;
+
+        let mut first = None ;
+        for_each_range_in(mode , root , |r| if first.is_none() { first = Some(*r) }) ;
+        assert_eq!(format!("{}" , first.unwrap()) , "0x10000000 => 0x10002fff -> 0x80000000 => 0x80002fff [RW-----] (0)") ;
+        assert_eq!(format!("{}" , RW | PteFlags::USER | PteFlags::DIRTY) , "RW-U--D") ;
+        assert_eq!(1 + count_tables(root , mode.top()) , 4) ;
+        unmap_all_in(mode , root) ;
+        dealloc(root as *mut Table as *mut u8) ;
+    }
+
+    #[test]
+    fn protect_changes_permissions_in_place(){
+        let (_heap , root) = kernel_root() ;
+        let mode = PagingMode::Sv39 ;
+        mapping_in(mode , root , va(0x4000_0000) , pa(0x8000_0000) , RW , 1) ;
+        mapping_in(mode , root , va(0x4020_0000) , pa(0x8020_0000) , RW | PteFlags::ACCESSED | PteFlags::RSW0 , 1) ;
+        mapping_in(mode , root , va(0x4040_0000) , pa(0x9000_0000) , RW , 0) ;
+        let leaf = |root: &Table , a| lookup_in(mode , root , va(a)).map(|r| (r.flags , r.level)) ;
+
+        // Covers the first megapage whole, it keeps its size
+        protect_in(mode , root , va(0x4000_0000)..va(0x4020_0000) , PteFlags::RX).unwrap() ;
+        assert_eq!(leaf(root , 0x4000_0000) , Some((PteFlags::RX | PteFlags::VALID , 1))) ;
+
+        // Part of the second one, it gets split and only the pages in range change
+        protect_in(mode , root , va(0x4030_0000)..va(0x4030_2000) , PteFlags::READ | PteFlags::USER).unwrap() ;
+        let kept = PteFlags::ACCESSED | PteFlags::RSW0 | PteFlags::VALID ;
+        assert_eq!(leaf(root , 0x4030_1000) , Some((PteFlags::READ | PteFlags::USER | kept , 0))) ;
+        assert_eq!(leaf(root , 0x4030_2000) , Some((RW | kept , 0))) ;
+        assert_eq!(leaf(root , 0x402f_f000) , Some((RW | kept , 0))) ;
+        assert_eq!(translate_in(mode , root , va(0x4030_1008)) , Some(pa(0x8030_1008))) ;
+
+        // A hole after the 4 KiB page, nothing changes
+        assert_eq!(protect_in(mode , root , va(0x4030_0000)..va(0x4040_2000) , PteFlags::READ) , Err(ProtectError::NotMapped(va(0x4040_1000)))) ;
+        assert_eq!(leaf(root , 0x4030_2000) , Some((RW | kept , 0))) ;
+        assert_eq!(leaf(root , 0x4040_0000) , Some((RW | PteFlags::VALID , 0))) ;
+        unmap_all_in(mode , root) ;
+        dealloc(root as *mut Table as *mut u8) ;
+    }
+
+    #[test]
+    fn sv48_and_sv57_walks(){
+        for mode in [PagingMode::Sv39 , PagingMode::Sv48 , PagingMode::Sv57]{
+            let (_heap , root) = kernel_root() ;
+            let top = mode.top() ;
+            // Two root entries up, so it gets tables of its own on every level
+            let high = 2 * level_size(top) + 0x1234_5000 ;
+            mapping_in(mode , root , va(high) , pa(0x8000_0000) , RW , 0) ;
+            mapping_in(mode , root , va(0x1000) , pa(0x8000_1000) , RW , 0) ;
+            // The largest leaf this mode has, at the root
+            mapping_in(mode , root , va(level_size(top)) , pa(0) , RW , top) ;
+            assert_eq!(count_tables(root , top) , 2 * (mode.levels() - 1)) ;
+
+            assert_eq!(translate_in(mode , root , va(high + 8)) , Some(pa(0x8000_0008))) ;
+            assert_eq!(translate_in(mode , root , va(0x1010)) , Some(pa(0x8000_1010))) ;
+            assert_eq!(translate_in(mode , root , va(level_size(top) + 0x1234)) , Some(pa(0x1234))) ;
+            assert_eq!(translate_in(mode , root , va(0x2000)) , None) ;
+            assert_eq!(mode.satp(pa(0x8020_0000)) >> 60 , mode as usize) ;
+
+            unmap_range_in(mode , root , va(0) , va(0x10_0000) , |_| {}).unwrap() ;
+            assert_eq!(translate_in(mode , root , va(0x1010)) , None) ;
+            assert_eq!(count_tables(root , top) , mode.levels() - 1) ;
+            assert_eq!(unmap_all_in(mode , root) , mode.levels() - 1) ;
+            assert!(root.is_empty()) ;
+            dealloc(root as *mut Table as *mut u8) ;
+        }
+    }
+
+    // The caller's line is what ends up in the tracker, tables the walk makes for itself aren't there
+    #[cfg(feature = "alloc_track")]
+    #[test]
+    fn tracks_callers_but_not_tables(){
+        let (_heap , root) = kernel_root() ;
+        let snap = crate::track::snapshot() ;
+        let line = line!() + 1 ;
+        let ptr = PageAllocator.allocate(Layout::new::<[u8 ; 100]>()).unwrap().cast::<u8>() ;
+        mapping(root , va(0x4000_0000) , pa(0x8000_0000) , RW , 0) ;
+        let mut mine = Vec::new() ;
+        crate::track::for_each_since(snap , |r|{
+            if r.caller.file() == file!(){
+                mine.push((r.ptr , r.caller.line())) ;
+            }
+        }) ;
+        assert_eq!(mine , vec![(ptr.as_ptr() as usize , line)]) ;
+        unsafe{ PageAllocator.deallocate(ptr , Layout::new::<[u8 ; 100]>()) } ;
+        unmap_all(root) ;
+        dealloc(root as *mut Table as *mut u8) ;
+    }
+}
@@ -0,0 +1,462 @@
+use crate::kmem::{KernelPages, PageSource};
+use crate::page::{align_val, PAGE_SIZE};
+use crate::lock::Mutex;
+use core::{mem::size_of, ptr::null_mut};
+
+/// Every slab is SLAB_PAGES pages, aligned to its own size so the header of the slab an
+/// object lives in is found by masking the object address.
+pub const SLAB_PAGES: usize = 4;
+pub const SLAB_SIZE: usize = SLAB_PAGES * PAGE_SIZE;
+const SLAB_ORDER: usize = SLAB_SIZE.trailing_zeros() as usize;
+
+/// Written into every slab header so kfree can tell a slab object from a stray pointer
+const SLAB_MAGIC: usize = 0x51ab_51ab_51ab_51ab;
+
+/// Smallest and largest size class served by the slab caches, in bytes. Behind the slab
+/// header only 7 objects of 2048 bytes would fit a slab, so those go to the list allocator.
+pub const MIN_CLASS: usize = 16;
+pub const MAX_CLASS: usize = 1024;
+const NUM_CLASSES: usize = 7; // 16, 32, ..., 1024
+
+/// A free object holds the link to the next free object of its slab
+struct FreeObj {
+    next: *mut FreeObj,
+}
+
+/// Header at the start of every slab
+struct Slab {
+    magic: usize,
+    cache: usize, // The KmemCache this slab belongs to
+    owner: usize, // The KernelCache holding that KmemCache, 0 for other caches
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObj,
+    inuse: usize,
+}
+
+/// Snapshot of a cache
+#[derive(Copy, Clone, Debug)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub obj_size: usize,
+    pub slabs: usize,
+    pub inuse: usize,
+    pub capacity: usize,
+}
+
+/// A cache of equally sized objects carved out of slabs from a PageSource.
+/// The kernel keeps one per size class for kmalloc, and subsystems can make their own
+/// named caches for fixed-size objects. A cache must not move once it has handed out
+/// objects, slabs point back at it (make it a static or keep it boxed).
+pub struct KmemCache<P: PageSource> {
+    name: &'static str,
+    obj_size: usize,   // Distance between two objects, a multiple of the alignment
+    first_obj: usize,  // Offset of the first object from the slab header
+    per_slab: usize,
+    partial: *mut Slab, // Slabs with at least one free object
+    slabs: usize,
+    inuse: usize,
+    owner: usize, // Goes into the header of every new slab, see KernelCache
+    pages: P,
+}
+
+impl<P: PageSource> KmemCache<P> {
+    /// Cache of objects of size bytes aligned to align (a power of two)
+    pub const fn new(name: &'static str, size: usize, align: usize, pages: P) -> Self {
+        assert!(align.is_power_of_two());
+        let align_order = align.trailing_zeros() as usize;
+        // Free objects hold a pointer, so they can't be smaller than one
+        let size = if size < size_of::<FreeObj>() { size_of::<FreeObj>() } else { size };
+        let obj_size = align_val(size, align_order);
+        let first_obj = align_val(size_of::<Slab>(), align_order);
+        assert!(first_obj + obj_size <= SLAB_SIZE, "object too big for a slab");
+        KmemCache {
+            name,
+            obj_size,
+            first_obj,
+            per_slab: (SLAB_SIZE - first_obj) / obj_size,
+            partial: null_mut(),
+            slabs: 0,
+            inuse: 0,
+            owner: 0,
+            pages,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn get_obj_size(&self) -> usize {
+        self.obj_size
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            name: self.name,
+            obj_size: self.obj_size,
+            slabs: self.slabs,
+            inuse: self.inuse,
+            capacity: self.slabs * self.per_slab,
+        }
+    }
+
+    /// Get a fresh slab and thread all of its objects onto its free list
+    unsafe fn grow(&mut self) -> *mut Slab {
+        // We write the slab header and the free list into it right away, so the pages
+        // must come back already mapped (KernelPages: kinit maps the whole page heap)
+        let mem = self.pages.alloc_pages_aligned(SLAB_PAGES, SLAB_ORDER);
+        if mem.is_null() {
+            return null_mut();
+        }
+        let slab = mem as *mut Slab;
+        unsafe {
+            (*slab).magic = SLAB_MAGIC;
+            (*slab).cache = self as *mut Self as usize;
+            (*slab).owner = self.owner;
+            (*slab).next = null_mut();
+            (*slab).prev = null_mut();
+            (*slab).inuse = 0;
+            (*slab).free = null_mut();
+            // Link them back to front so the first object is handed out first
+            for i in (0..self.per_slab).rev() {
+                let obj = mem.add(self.first_obj + i * self.obj_size) as *mut FreeObj;
+                (*obj).next = (*slab).free;
+                (*slab).free = obj;
+            }
+        }
+        self.slabs += 1;
+        slab
+    }
+
+    unsafe fn push_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+            self.partial = slab;
+        }
+    }
+
+    unsafe fn remove_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            if !(*slab).prev.is_null() {
+                (*(*slab).prev).next = (*slab).next;
+            }
+            else {
+                self.partial = (*slab).next;
+            }
+            if !(*slab).next.is_null() {
+                (*(*slab).next).prev = (*slab).prev;
+            }
+            (*slab).next = null_mut();
+            (*slab).prev = null_mut();
+        }
+    }
+
+    /// One object, or null if the page source is out of memory
+    pub fn alloc(&mut self) -> *mut u8 {
+        unsafe {
+            if self.partial.is_null() {
+                let slab = self.grow();
+                if slab.is_null() {
+                    return null_mut();
+                }
+                self.push_partial(slab);
+            }
+            let slab = self.partial;
+            let obj = (*slab).free;
+            (*slab).free = (*obj).next;
+            (*slab).inuse += 1;
+            self.inuse += 1;
+            // A full slab leaves the partial list until something in it is freed
+            if (*slab).free.is_null() {
+                self.remove_partial(slab);
+            }
+            obj as *mut u8
+        }
+    }
+
+    /// Give an object back. Empty slabs are returned to the page source, unless
+    /// it is the only slab with free room left.
+    // ptr has to be something a cache handed out, slab_of checks the slab magic and
+    // we check that ptr is the start of one of our objects.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn free(&mut self, ptr: *mut u8) {
+        unsafe {
+            let slab = slab_of(ptr);
+            assert!(
+                (*slab).cache == self as *mut Self as usize,
+                "slab: {:p} does not belong to cache {}",
+                ptr,
+                self.name
+            );
+            let off = ptr as usize - slab as usize;
+            assert!(
+                off >= self.first_obj && (off - self.first_obj).is_multiple_of(self.obj_size),
+                "slab: {:p} is not the start of a {} object",
+                ptr,
+                self.name
+            );
+
+            let was_full = (*slab).free.is_null();
+            let obj = ptr as *mut FreeObj;
+            (*obj).next = (*slab).free;
+            (*slab).free = obj;
+            (*slab).inuse -= 1;
+            self.inuse -= 1;
+
+            if was_full {
+                self.push_partial(slab);
+            }
+            if (*slab).inuse == 0 && !((*slab).prev.is_null() && (*slab).next.is_null()) {
+                self.remove_partial(slab);
+                (*slab).magic = 0;
+                self.slabs -= 1;
+                self.pages.free_pages(slab as *mut u8);
+            }
+        }
+    }
+
+    /// Hand the empty slabs free keeps around back to the page source. Returns the bytes freed.
+    pub fn shrink(&mut self) -> usize {
+        let mut freed = 0;
+        unsafe {
+            let mut slab = self.partial;
+            while !slab.is_null() {
+                let next = (*slab).next;
+                if (*slab).inuse == 0 {
+                    self.remove_partial(slab);
+                    (*slab).magic = 0;
+                    self.slabs -= 1;
+                    self.pages.free_pages(slab as *mut u8);
+                    freed += SLAB_SIZE;
+                }
+                slab = next;
+            }
+        }
+        freed
+    }
+}
+
+// The slab lists only point into slabs the cache owns
+unsafe impl<P: PageSource + Send> Send for KmemCache<P> {}
+
+/// A named cache for kernel objects, e.g.
+/// `static TASKS: KernelCache = KernelCache::new("task", size_of::<Task>(), 16);`
+/// Its objects can be released with `TASKS.free(p)` or with kmem::kfree. Every slab it
+/// makes records the address of the KernelCache, so free can take the right lock from
+/// a bare object pointer.
+pub struct KernelCache {
+    cache: Mutex<KmemCache<KernelPages>>,
+}
+
+impl KernelCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        KernelCache {
+            cache: Mutex::new(KmemCache::new(name, size, align, KernelPages)),
+        }
+    }
+
+    /// One object, or null if the page allocator is out of memory
+    pub fn alloc(&'static self) -> *mut u8 {
+        let mut cache = self.cache.lock();
+        cache.owner = self as *const Self as usize;
+        cache.alloc()
+    }
+
+    pub fn free(&self, ptr: *mut u8) {
+        self.cache.lock().free(ptr)
+    }
+
+    pub fn shrink(&self) -> usize {
+        self.cache.lock().shrink()
+    }
+
+    pub fn get_obj_size(&self) -> usize {
+        self.cache.lock().get_obj_size()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().stats()
+    }
+}
+
+/// Header of the slab ptr points into, panics if ptr isn't in a slab
+unsafe fn slab_of(ptr: *mut u8) -> *mut Slab {
+    let slab = (ptr as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+    unsafe {
+        assert!((*slab).magic == SLAB_MAGIC, "slab: {:p} is not a slab object", ptr);
+    }
+    slab
+}
+
+// The size-class caches behind kmalloc
+static CLASSES: [KernelCache; NUM_CLASSES] = [
+    KernelCache::new("kmalloc-16", 16, 16),
+    KernelCache::new("kmalloc-32", 32, 32),
+    KernelCache::new("kmalloc-64", 64, 64),
+    KernelCache::new("kmalloc-128", 128, 128),
+    KernelCache::new("kmalloc-256", 256, 256),
+    KernelCache::new("kmalloc-512", 512, 512),
+    KernelCache::new("kmalloc-1024", 1024, 1024),
+];
+
+/// Index of the size class for sz bytes at the given alignment. Objects of a class
+/// are aligned to the class size, so the alignment just bumps the class.
+fn class_of(sz: usize, align: usize) -> usize {
+    let size = sz.max(align).max(MIN_CLASS).next_power_of_two();
+    (size.trailing_zeros() - MIN_CLASS.trailing_zeros()) as usize
+}
+
+/// Can a size class serve this request ?
+pub fn fits(sz: usize, align: usize) -> bool {
+    sz <= MAX_CLASS && align <= MAX_CLASS
+}
+
+/// Object from the size class that fits sz bytes at alignment align
+pub fn alloc(sz: usize, align: usize) -> *mut u8 {
+    assert!(fits(sz, align));
+    CLASSES[class_of(sz, align)].alloc()
+}
+
+/// The kernel cache the object at ptr came from, found through its slab header
+unsafe fn owner_of(ptr: *mut u8) -> &'static KernelCache {
+    unsafe {
+        let owner = (*slab_of(ptr)).owner as *const KernelCache;
+        assert!(!owner.is_null(), "slab: {:p} is not from a kernel cache", ptr);
+        &*owner
+    }
+}
+
+/// Free an object from any kernel cache (size class or named)
+// Like kfree, ptr has to be something a cache handed out. slab_of checks the magic
+// and the cache checks that ptr is the start of one of its objects.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn free(ptr: *mut u8) {
+    unsafe { owner_of(ptr).free(ptr) }
+}
+
+/// Can the object at ptr hold new_size bytes without moving ? Only if it stays in the
+/// same size class, we don't want a 16 byte Vec sitting on a 1024 byte object.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn resize_in_place(ptr: *mut u8, new_size: usize) -> bool {
+    let obj_size = unsafe { owner_of(ptr).get_obj_size() };
+    new_size <= obj_size && (new_size > obj_size / 2 || obj_size == MIN_CLASS)
+}
+
+/// Shrinker for the size-class caches (see oom.rs), kmem::init registers it
+pub fn shrink(bytes: usize) -> usize {
+    let mut freed = 0;
+    for cache in CLASSES.iter() {
+        if freed >= bytes {
+            break;
+        }
+        freed += cache.shrink();
+    }
+    freed
+}
+
+/// Call f with the stats of every size-class cache
+pub fn for_each_class<F: FnMut(CacheStats)>(mut f: F) {
+    for cache in CLASSES.iter() {
+        f(cache.stats());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::PageHeap;
+
+    fn cache(name: &'static str, size: usize, align: usize) -> Box<KmemCache<PageHeap>> {
+        let region = vec![0u8; 256 * PAGE_SIZE].leak();
+        Box::new(KmemCache::new(name, size, align, PageHeap::from_slice(region)))
+    }
+
+    #[test]
+    fn size_classes() {
+        assert_eq!(class_of(1, 8), 0);
+        assert_eq!(class_of(16, 8), 0);
+        assert_eq!(class_of(17, 8), 1);
+        assert_eq!(class_of(24, 64), 2);
+        assert_eq!(class_of(1024, 8), NUM_CLASSES - 1);
+        assert!(!fits(1025, 8) && !fits(2048, 8));
+    }
+
+    #[test]
+    fn objects_are_aligned_and_distinct() {
+        let mut c = cache("test-48", 48, 16);
+        let ptrs: Vec<_> = (0..500).map(|_| c.alloc()).collect();
+        let mut sorted: Vec<_> = ptrs.iter().map(|p| *p as usize).collect();
+        sorted.sort();
+        assert!(sorted.windows(2).all(|w| w[1] - w[0] >= 48));
+        assert!(ptrs.iter().all(|p| (*p as usize).is_multiple_of(16)));
+        assert_eq!(c.stats().inuse, 500);
+        assert!(c.stats().slabs > 1);
+        for p in ptrs {
+            c.free(p);
+        }
+        assert_eq!(c.stats().inuse, 0);
+        assert_eq!(c.stats().slabs, 1);
+    }
+
+    #[test]
+    fn empty_slabs_go_back_to_the_pages() {
+        let mut c = cache("test-2048", 2048, 2048);
+        let ptrs: Vec<_> = (0..40).map(|_| c.alloc()).collect();
+        let used = c.pages.stats().used;
+        assert!(used >= 40 / c.per_slab * SLAB_PAGES);
+        for p in ptrs.into_iter().rev() {
+            c.free(p);
+        }
+        assert_eq!(c.pages.stats().used, SLAB_PAGES);
+    }
+
+    #[test]
+    fn shrink_releases_the_last_empty_slab() {
+        let mut c = cache("test-64", 64, 64);
+        let p = c.alloc();
+        c.free(p);
+        assert_eq!(c.stats().slabs, 1);
+        assert_eq!(c.shrink(), SLAB_SIZE);
+        assert_eq!((c.stats().slabs, c.pages.stats().used), (0, 0));
+        assert!(!c.alloc().is_null());
+    }
+
+    #[test]
+    fn exhaustion_returns_null() {
+        let mut c = cache("test-1024", 1024, 8);
+        let mut n = 0;
+        while !c.alloc().is_null() {
+            n += 1;
+        }
+        assert_eq!(n, c.stats().capacity);
+    }
+
+    #[test]
+    fn free_finds_the_kernel_cache() {
+        static TASKS: KernelCache = KernelCache::new("test-task", 40, 8);
+        let _heap = crate::page::init_test_heap();
+        let a = TASKS.alloc();
+        let b = TASKS.alloc();
+        assert_eq!(TASKS.stats().inuse, 2);
+        free(a);
+        assert!(resize_in_place(b, 33) && !resize_in_place(b, 41));
+        free(b);
+        assert_eq!(TASKS.stats().inuse, 0);
+        assert_eq!(TASKS.shrink(), SLAB_SIZE);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not belong")]
+    fn free_into_wrong_cache_panics() {
+        let mut a = cache("a", 32, 8);
+        let mut b = cache("b", 32, 8);
+        let p = a.alloc();
+        b.alloc();
+        b.free(p);
+    }
+}
@@ -0,0 +1,71 @@
+// Helpers shared by the host tests of the allocators
+
+/// Small xorshift so the random tests are reproducible without pulling in a crate
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+}
+
+/// A DTB with one /memory range and one /reserved-memory child, both with 2 address and
+/// 2 size cells
+pub fn dtb_blob(mem: (usize, usize), rsv: (usize, usize)) -> Vec<u8> {
+    let strings = b"#address-cells\0#size-cells\0reg\0";
+    let mut dt: Vec<u8> = Vec::new();
+    let word = |dt: &mut Vec<u8>, w: u32| dt.extend_from_slice(&w.to_be_bytes());
+    let node = |dt: &mut Vec<u8>, name: &str| {
+        dt.extend_from_slice(&1u32.to_be_bytes());
+        dt.extend_from_slice(name.as_bytes());
+        dt.resize((dt.len() + 4) & !3, 0);
+    };
+    let cells = |dt: &mut Vec<u8>| {
+        for name_off in [0u32, 15] {
+            dt.extend_from_slice(&[3u32, 4, name_off, 2].map(u32::to_be_bytes).concat());
+        }
+    };
+    let reg = |dt: &mut Vec<u8>, (start, size): (usize, usize)| {
+        dt.extend_from_slice(&[3u32, 16, 27].map(u32::to_be_bytes).concat());
+        dt.extend_from_slice(&(start as u64).to_be_bytes());
+        dt.extend_from_slice(&(size as u64).to_be_bytes());
+    };
+    node(&mut dt, "");
+    cells(&mut dt);
+    node(&mut dt, "memory@0");
+    reg(&mut dt, mem);
+    word(&mut dt, 2);
+    node(&mut dt, "reserved-memory");
+    cells(&mut dt);
+    node(&mut dt, "firmware@0");
+    reg(&mut dt, rsv);
+    word(&mut dt, 2);
+    word(&mut dt, 2);
+    word(&mut dt, 2);
+    word(&mut dt, 9);
+
+    // Header, an empty memory reservation block, the structure block, the strings
+    let struct_off = 40 + 16;
+    let strings_off = struct_off + dt.len();
+    let total = strings_off + strings.len();
+    let header = [
+        0xd00d_feedu32,
+        total as u32,
+        struct_off as u32,
+        strings_off as u32,
+        40,
+        17,
+        16,
+        0,
+        strings.len() as u32,
+        dt.len() as u32,
+    ];
+    let mut blob: Vec<u8> = header.map(u32::to_be_bytes).concat();
+    blob.resize(struct_off, 0);
+    blob.extend_from_slice(&dt);
+    blob.extend_from_slice(strings);
+    blob
+}
@@ -0,0 +1,228 @@
+// Leak tracker, built with the alloc_track feature. kmalloc/kfree and the page allocator
+// record every live allocation here with its size and the location of the caller
+// (their public functions are #[track_caller] in this mode). Take a snapshot before a
+// test scenario and dump what is still live from after it to find the leaks.
+// The global allocator is the exception: GlobalAlloc::alloc can't be #[track_caller], so
+// Box and Vec allocations are recorded as Kind::Global with their Layout and no useful caller.
+use core::panic::Location;
+use crate::lock::{Mutex, MutexGuard};
+
+/// Live allocations we can keep track of at once, more are counted as dropped
+pub const MAX_TRACKED: usize = 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Kmem,
+    Pages,
+    /// Box, Vec and the rest of alloc through the global allocator. Their caller is always
+    /// the same line in kmem.rs, the Layout's size and align are all there is to go by.
+    Global { align: usize },
+}
+
+/// One live allocation
+#[derive(Copy, Clone, Debug)]
+pub struct Record {
+    pub ptr: usize,
+    pub size: usize, // Bytes
+    pub kind: Kind,
+    pub caller: &'static Location<'static>,
+    pub seq: u64, // Allocations made before this one
+}
+
+/// Sequence number to hand to dump_since / for_each_since later
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Snapshot(u64);
+
+/// Table of live allocations
+pub struct Tracker {
+    records: [Option<Record>; MAX_TRACKED],
+    live: usize,
+    seq: u64,
+    dropped: usize,
+}
+
+impl Tracker {
+    pub const fn new() -> Self {
+        Tracker {
+            records: [None; MAX_TRACKED],
+            live: 0,
+            seq: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn add(&mut self, ptr: *mut u8, size: usize, kind: Kind, caller: &'static Location<'static>) {
+        if ptr.is_null() {
+            return;
+        }
+        let seq = self.seq;
+        self.seq += 1;
+        match self.records.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => {
+                *slot = Some(Record { ptr: ptr as usize, size, kind, caller, seq });
+                self.live += 1;
+            }
+            None => self.dropped += 1,
+        }
+    }
+
+    /// Forget ptr. Pointers we never recorded (made before tracking had room) are ignored.
+    pub fn remove(&mut self, ptr: *mut u8, kind: Kind) {
+        let slot = self
+            .records
+            .iter_mut()
+            .find(|r| matches!(r, Some(r) if r.ptr == ptr as usize && r.kind == kind));
+        if let Some(slot) = slot {
+            *slot = None;
+            self.live -= 1;
+        }
+    }
+
+    /// ptr was resized in place
+    pub fn resize(&mut self, ptr: *mut u8, kind: Kind, size: usize) {
+        let r = self.records.iter_mut().flatten().find(|r| r.ptr == ptr as usize && r.kind == kind);
+        if let Some(r) = r {
+            r.size = size;
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot(self.seq)
+    }
+
+    /// Number of live allocations we know of
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    /// Allocations that didn't fit in the table
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Call f for every allocation made after snap that is still live, oldest first
+    pub fn for_each_since<F: FnMut(&Record)>(&self, snap: Snapshot, mut f: F) {
+        let mut next = snap.0;
+        // The table is unordered, so pick the records in sequence order
+        loop {
+            let r = self
+                .records
+                .iter()
+                .flatten()
+                .filter(|r| r.seq >= next)
+                .min_by_key(|r| r.seq);
+            match r {
+                Some(r) => {
+                    f(r);
+                    next = r.seq + 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Print every allocation made after snap that is still live
+    pub fn dump_since(&self, snap: Snapshot) {
+        let mut count = 0;
+        let mut bytes = 0;
+        println!("Live allocations since #{}:", snap.0);
+        self.for_each_since(snap, |r| {
+            println!(
+                "  #{:<6} {:#010x} {:>8} bytes  {:?}  {}",
+                r.seq, r.ptr, r.size, r.kind, r.caller
+            );
+            count += 1;
+            bytes += r.size;
+        });
+        println!("{} allocations, {} bytes", count, bytes);
+        if self.dropped > 0 {
+            println!("({} allocations were not tracked, the table was full)", self.dropped);
+        }
+    }
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The kernel's tracker, fed by kmem and page
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+fn tracker() -> MutexGuard<'static, Tracker> {
+    TRACKER.lock()
+}
+
+pub fn add(ptr: *mut u8, size: usize, kind: Kind, caller: &'static Location<'static>) {
+    tracker().add(ptr, size, kind, caller)
+}
+
+pub fn remove(ptr: *mut u8, kind: Kind) {
+    tracker().remove(ptr, kind)
+}
+
+pub fn resize(ptr: *mut u8, kind: Kind, size: usize) {
+    tracker().resize(ptr, kind, size)
+}
+
+/// Mark the start of a scenario, see dump_since
+pub fn snapshot() -> Snapshot {
+    tracker().snapshot()
+}
+
+pub fn live() -> usize {
+    tracker().live()
+}
+
+pub fn for_each_since<F: FnMut(&Record)>(snap: Snapshot, f: F) {
+    tracker().for_each_since(snap, f)
+}
+
+/// Dump what was allocated after snap and is still live over the UART
+pub fn dump_since(snap: Snapshot) {
+    tracker().dump_since(snap)
+}
+
+/// Dump every live allocation over the UART
+pub fn dump() {
+    tracker().dump_since(Snapshot(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_callers_and_diffs_snapshots() {
+        let mut t = Box::new(Tracker::new());
+        let a = 0x1000 as *mut u8;
+        let b = 0x2000 as *mut u8;
+        let c = 0x3000 as *mut u8;
+        t.add(a, 16, Kind::Kmem, Location::caller());
+        let snap = t.snapshot();
+        let here = Location::caller();
+        t.add(b, 32, Kind::Kmem, here);
+        t.add(c, 4096, Kind::Pages, here);
+        // Same address, other allocator: must not remove c
+        t.remove(c, Kind::Kmem);
+        t.remove(b, Kind::Kmem);
+        assert_eq!(t.live(), 2);
+
+        let mut since = Vec::new();
+        t.for_each_since(snap, |r| since.push((r.ptr, r.size, r.kind, r.caller.line())));
+        assert_eq!(since, vec![(0x3000, 4096, Kind::Pages, here.line())]);
+    }
+
+    #[test]
+    fn full_table_counts_dropped() {
+        let mut t = Box::new(Tracker::new());
+        for i in 0..MAX_TRACKED + 3 {
+            t.add((8 * (i + 1)) as *mut u8, 8, Kind::Kmem, Location::caller());
+        }
+        assert_eq!((t.live(), t.dropped()), (MAX_TRACKED, 3));
+        t.remove(8 as *mut u8, Kind::Kmem);
+        t.add(0x10_0000 as *mut u8, 8, Kind::Kmem, Location::caller());
+        assert_eq!(t.live(), MAX_TRACKED);
+    }
+}
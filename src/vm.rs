@@ -0,0 +1,463 @@
+// Address spaces. An AddressSpace owns a root page table and everything under it, so
+// processes and kernel threads get their memory map as one object instead of a bare
+// *mut Table handed to the free functions in page.rs.
+// Frames the space owns (allocated by alloc_range, or referenced by map_frame) carry
+// OWNED in their leaf, a software bit of the PTE, and go back to the page allocator
+// with page::put when they are unmapped or the space is dropped. Everything else
+// (MMIO, the kernel image) is only mapped, never freed.
+use core::fmt;
+use core::ops::Range;
+use crate::oom::AllocFailure;
+use crate::page::{
+    self, MapError, MapRange, PhysAddr, ProtectError, PteFlags, Table, VirtAddr, PAGE_ORDER, PAGE_SIZE,
+};
+
+/// Marks the leaves whose frames the address space holds a reference on
+const OWNED: PteFlags = PteFlags::RSW0;
+
+/// Why an address space operation failed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    /// An address or size isn't page aligned, or the range is empty
+    Misaligned,
+    /// Something is mapped here already
+    AlreadyMapped(VirtAddr),
+    /// Nothing is mapped here
+    NotMapped(VirtAddr),
+    /// None of RWX is set, that would make a pointer to a table rather than a leaf, or W
+    /// is set without R, which the hardware reserves
+    BadFlags,
+    /// No pages left for a frame or the root table
+    OutOfMemory,
+    /// The address isn't the start of a live page heap allocation, or its reference
+    /// count can't go any higher
+    BadFrame(PhysAddr),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::Misaligned => write!(f, "range not page aligned"),
+            VmError::AlreadyMapped(va) => write!(f, "{:#x} is already mapped", va),
+            VmError::NotMapped(va) => write!(f, "{:#x} is not mapped", va),
+            VmError::BadFlags => write!(f, "no RWX permission, or W without R"),
+            VmError::OutOfMemory => write!(f, "out of memory"),
+            VmError::BadFrame(pa) => {
+                write!(f, "{:#x} is not a frame we can take a reference on", pa)
+            }
+        }
+    }
+}
+
+impl From<AllocFailure> for VmError {
+    fn from(_: AllocFailure) -> Self {
+        VmError::OutOfMemory
+    }
+}
+
+impl From<MapError> for VmError {
+    fn from(e: MapError) -> Self {
+        match e {
+            MapError::OutOfMemory => VmError::OutOfMemory,
+            MapError::Mapped(va) => VmError::AlreadyMapped(va),
+        }
+    }
+}
+
+impl From<ProtectError> for VmError {
+    fn from(e: ProtectError) -> Self {
+        match e {
+            ProtectError::NotMapped(va) => VmError::NotMapped(va),
+            ProtectError::OutOfMemory => VmError::OutOfMemory,
+        }
+    }
+}
+
+/// A root page table with its mappings, in the paging mode probed at boot
+pub struct AddressSpace {
+    root: *mut Table,
+}
+
+// The root and the tables under it belong to the address space alone
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+    /// An empty address space
+    pub fn new() -> Result<Self, VmError> {
+        let root = page::try_zero_alloc_pages_aligned(1, PAGE_ORDER)? as *mut Table;
+        Ok(AddressSpace { root })
+    }
+
+    pub fn root(&self) -> &Table {
+        unsafe { &*self.root }
+    }
+
+    fn root_mut(&mut self) -> &mut Table {
+        unsafe { &mut *self.root }
+    }
+
+    pub fn root_pa(&self) -> PhysAddr {
+        PhysAddr::from_ptr(self.root)
+    }
+
+    /// What to write to satp to switch to this address space (ASID 0)
+    pub fn satp(&self) -> usize {
+        page::paging_mode().satp(self.root_pa())
+    }
+
+    /// Map range to the physical memory starting at pa, with the largest leaves the
+    /// alignment of both allows. The frames aren't ours, unmapping leaves them alone.
+    /// If we run out of pages for the tables, what was mapped so far is undone.
+    pub fn map_range(
+        &mut self,
+        range: Range<VirtAddr>,
+        pa: PhysAddr,
+        flags: PteFlags,
+    ) -> Result<(), VmError> {
+        check_range(&range)?;
+        check_flags(flags)?;
+        if !pa.is_aligned(PAGE_SIZE) {
+            return Err(VmError::Misaligned);
+        }
+        self.check_unmapped(&range)?;
+        let levels = page::paging_mode().levels();
+        let (mut va, mut pa) = (range.start, pa);
+        while va < range.end {
+            let left = range.end - va;
+            let level = (0..levels)
+                .rev()
+                .find(|&l| {
+                    let size = page::level_size(l);
+                    va.is_aligned(size) && pa.is_aligned(size) && size <= left
+                })
+                .unwrap_or(0);
+            let mapped = page::try_mapping(self.root_mut(), va, pa, flags.difference(OWNED), level);
+            if let Err(e) = mapped {
+                self.undo(range.start..va);
+                return Err(e.into());
+            }
+            va += page::level_size(level);
+            pa += page::level_size(level);
+        }
+        Ok(())
+    }
+
+    /// Back range with new zeroed frames the address space owns. If we run out of
+    /// frames or table pages halfway, what was mapped so far is undone.
+    pub fn alloc_range(&mut self, range: Range<VirtAddr>, flags: PteFlags) -> Result<(), VmError> {
+        check_range(&range)?;
+        check_flags(flags)?;
+        self.check_unmapped(&range)?;
+        let mut va = range.start;
+        while va < range.end {
+            // One allocation per frame, so each has a reference count of its own
+            let mapped = page::try_zero_alloc_pages_aligned(1, PAGE_ORDER)
+                .map_err(VmError::from)
+                .and_then(|frame| {
+                    let pa = PhysAddr::from_ptr(frame);
+                    page::try_mapping(self.root_mut(), va, pa, flags | OWNED, 0).map_err(|e| {
+                        page::dealloc(frame);
+                        VmError::from(e)
+                    })
+                });
+            if let Err(e) = mapped {
+                self.undo(range.start..va);
+                return Err(e);
+            }
+            va += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Map the page heap frame at pa (the start of an allocation) at va and take a
+    /// reference on it, so it stays around as long as this mapping does
+    pub fn map_frame(
+        &mut self,
+        va: VirtAddr,
+        pa: PhysAddr,
+        flags: PteFlags,
+    ) -> Result<(), VmError> {
+        if !pa.is_aligned(PAGE_SIZE) {
+            return Err(VmError::Misaligned);
+        }
+        let range = va..va + PAGE_SIZE;
+        check_range(&range)?;
+        check_flags(flags)?;
+        self.check_unmapped(&range)?;
+        // Check before the leaf goes in, page::get panics on anything but a live run
+        match page::try_ref_count(pa.as_ptr()) {
+            Some(refs) if refs < u16::MAX as usize => {}
+            _ => return Err(VmError::BadFrame(pa)),
+        }
+        page::try_mapping(self.root_mut(), va, pa, flags | OWNED, 0)?;
+        page::get(pa.as_ptr());
+        Ok(())
+    }
+
+    /// Remove whatever is mapped in range and drop our reference on the frames we own.
+    /// A large leaf at either end that reaches outside the range needs a page to split,
+    /// if there is none nothing is unmapped.
+    pub fn unmap_range(&mut self, range: Range<VirtAddr>) -> Result<(), VmError> {
+        page::unmap_range_with(self.root_mut(), range.start, range.end, put_owned)?;
+        Ok(())
+    }
+
+    // Take down what a failed map_range or alloc_range had mapped of range. It is made of
+    // whole leaves, so nothing has to be split and this can't run out of pages.
+    fn undo(&mut self, range: Range<VirtAddr>) {
+        page::unmap_range_with(self.root_mut(), range.start, range.end, put_owned)
+            .expect("vm: undo split a leaf");
+    }
+
+    /// Give every page in range the RWX and U bits of flags, see page::protect. All of it
+    /// has to be mapped and a large leaf at either end may need a page to split, nothing
+    /// is changed otherwise.
+    pub fn protect(&mut self, range: Range<VirtAddr>, flags: PteFlags) -> Result<(), VmError> {
+        check_range(&range)?;
+        check_flags(flags)?;
+        page::protect(self.root_mut(), range, flags).map_err(VmError::from)
+    }
+
+    pub fn translate(&self, va: VirtAddr) -> Option<PhysAddr> {
+        page::translate(self.root(), va)
+    }
+
+    /// Frames we hold a reference on
+    pub fn owned_frames(&self) -> usize {
+        let mut frames = 0;
+        page::for_each_range(self.root(), |r| {
+            if r.flags.contains(OWNED) {
+                frames += r.size / PAGE_SIZE;
+            }
+        });
+        frames
+    }
+
+    /// Pages the page tables take, the root included
+    pub fn table_pages(&self) -> usize {
+        page::table_pages(self.root())
+    }
+
+    /// Print the mappings, see page::print_table
+    pub fn print(&self) {
+        page::print_table(self.root())
+    }
+
+    // AlreadyMapped with the first address in range that has a leaf
+    fn check_unmapped(&self, range: &Range<VirtAddr>) -> Result<(), VmError> {
+        match page::first_mapped(self.root(), range.start, range.end) {
+            Some(va) => Err(VmError::AlreadyMapped(va)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Nobody runs on this table any more (we had the only handle on it), so the
+        // frames can go before the tables that map them
+        page::for_each_range(self.root(), put_owned);
+        page::unmap_all(self.root_mut());
+        page::dealloc(self.root as *mut u8);
+    }
+}
+
+fn check_flags(flags: PteFlags) -> Result<(), VmError> {
+    if !flags.is_leaf() || (flags.contains(PteFlags::WRITE) && !flags.contains(PteFlags::READ)) {
+        return Err(VmError::BadFlags);
+    }
+    Ok(())
+}
+
+fn check_range(range: &Range<VirtAddr>) -> Result<(), VmError> {
+    if range.start >= range.end
+        || !range.start.is_aligned(PAGE_SIZE)
+        || !range.end.is_aligned(PAGE_SIZE)
+    {
+        return Err(VmError::Misaligned);
+    }
+    Ok(())
+}
+
+// Drop our reference on every frame of an owned leaf
+fn put_owned(r: &MapRange) {
+    if r.flags.contains(OWNED) {
+        for off in (0..r.size).step_by(PAGE_SIZE) {
+            page::put((r.pa + off).as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn va(addr: usize) -> VirtAddr {
+        VirtAddr::new(addr)
+    }
+
+    fn pa(addr: usize) -> PhysAddr {
+        PhysAddr::new(addr)
+    }
+
+    #[test]
+    fn owned_frames_go_back_on_unmap_and_drop() {
+        let _heap = page::init_test_heap();
+        let mut space = AddressSpace::new().unwrap();
+        space.alloc_range(va(0x1000_0000)..va(0x1000_4000), PteFlags::USER_RW).unwrap();
+        // Device memory, one megapage and a page, isn't ours
+        space.map_range(va(0x4000_0000)..va(0x4020_1000), pa(0x1000_0000), PteFlags::RW).unwrap();
+        assert_eq!(space.owned_frames(), 4);
+        assert_eq!(space.translate(va(0x4020_0010)), Some(pa(0x1020_0010)));
+        assert_eq!(
+            space.alloc_range(va(0x1000_3000)..va(0x1000_5000), PteFlags::RW),
+            Err(VmError::AlreadyMapped(va(0x1000_3000)))
+        );
+        assert_eq!(
+            space.map_range(va(0x3ff0_0000)..va(0x4000_1000), pa(0), PteFlags::RW),
+            Err(VmError::AlreadyMapped(va(0x4000_0000)))
+        );
+
+        // A frame someone else allocated stays theirs after we let go of it
+        let frame = page::zero_alloc(1);
+        space.map_frame(va(0x2000_0000), PhysAddr::from_ptr(frame), PteFlags::RW).unwrap();
+        let shared = space.translate(va(0x1000_1000)).unwrap();
+        page::get(shared.as_ptr());
+        space.unmap_range(va(0x1000_0000)..va(0x1000_2000)).unwrap();
+        space.unmap_range(va(0x2000_0000)..va(0x2000_1000)).unwrap();
+        assert_eq!(space.owned_frames(), 2);
+        assert_eq!(page::ref_count(shared.as_ptr()), 1);
+        assert_eq!(page::ref_count(frame), 1);
+        assert!(page::put(shared.as_ptr()) && page::put(frame));
+
+        let ppn = space.root_pa().page_num().as_usize();
+        assert_eq!(space.satp(), ((page::paging_mode() as usize) << 60) | ppn);
+        drop(space);
+    }
+
+    #[test]
+    fn drop_gives_back_every_page() {
+        let _heap = page::init_test_heap();
+        let free = page::stats().free;
+        let mut space = AddressSpace::new().unwrap();
+        // Far apart, so each gets tables of its own, and a megapage protect splits
+        space.alloc_range(va(0x1000_0000)..va(0x1000_3000), PteFlags::RW).unwrap();
+        space.alloc_range(va(0x7f_f000_0000)..va(0x7f_f000_1000), PteFlags::RW).unwrap();
+        space.map_range(va(0x4000_0000)..va(0x4040_0000), pa(0x8000_0000), PteFlags::RW).unwrap();
+        space.protect(va(0x4010_0000)..va(0x4010_1000), PteFlags::READ).unwrap();
+        let frame = page::zero_alloc(1);
+        space.map_frame(va(0x2000_0000), PhysAddr::from_ptr(frame), PteFlags::RW).unwrap();
+        assert!(!page::put(frame));
+        assert_eq!(page::stats().free, free - space.table_pages() - space.owned_frames());
+        drop(space);
+        assert_eq!(page::stats().free, free);
+    }
+
+    #[test]
+    fn map_frame_rejects_what_isnt_a_frame() {
+        let _heap = page::init_test_heap();
+        let mut space = AddressSpace::new().unwrap();
+        let run = page::zero_alloc(2);
+        let second = PhysAddr::from_ptr(run) + PAGE_SIZE;
+        for bad in [pa(0x8000_0000), second] {
+            assert_eq!(
+                space.map_frame(va(0x2000_0000), bad, PteFlags::RW),
+                Err(VmError::BadFrame(bad))
+            );
+        }
+        assert_eq!(space.translate(va(0x2000_0000)), None);
+        assert_eq!(space.table_pages(), 1);
+        page::dealloc(run);
+        let freed = PhysAddr::from_ptr(run);
+        assert_eq!(
+            space.map_frame(va(0x2000_0000), freed, PteFlags::RW),
+            Err(VmError::BadFrame(freed))
+        );
+    }
+
+    // Every free page of the test heap, one at a time
+    fn take_all_pages() -> Vec<*mut u8> {
+        let mut pages = Vec::new();
+        loop {
+            let p = page::alloc(1);
+            if p.is_null() {
+                return pages;
+            }
+            pages.push(p);
+        }
+    }
+
+    #[test]
+    fn running_out_halfway_undoes_the_range() {
+        let _heap = page::init_test_heap();
+        let mut space = AddressSpace::new().unwrap();
+        // Take all but a few pages. With one or two left the walk runs out of tables,
+        // with more it gets its tables and runs out of frames.
+        let mut hog = take_all_pages();
+        for spare in 1..6 {
+            for p in hog.drain(hog.len() - spare..) {
+                page::dealloc(p);
+            }
+            assert_eq!(
+                space.alloc_range(va(0x1000_0000)..va(0x1000_8000), PteFlags::RW),
+                Err(VmError::OutOfMemory)
+            );
+            assert_eq!(space.table_pages(), 1);
+            assert_eq!(page::stats().free, spare);
+            hog.extend((0..spare).map(|_| page::alloc(1)));
+        }
+        assert_eq!(
+            space.map_range(va(0x1000_0000)..va(0x1000_1000), pa(0x8000_0000), PteFlags::RW),
+            Err(VmError::OutOfMemory)
+        );
+        for p in hog {
+            page::dealloc(p);
+        }
+        space.alloc_range(va(0x1000_0000)..va(0x1000_8000), PteFlags::RW).unwrap();
+        assert_eq!(space.owned_frames(), 8);
+    }
+
+    fn rwx(space: &AddressSpace, addr: usize) -> PteFlags {
+        page::lookup(space.root(), va(addr)).unwrap().flags & PteFlags::RWX
+    }
+
+    #[test]
+    fn protect_changes_flags_and_keeps_ownership() {
+        let _heap = page::init_test_heap();
+        let mut space = AddressSpace::new().unwrap();
+        space.alloc_range(va(0x1000_0000)..va(0x1000_3000), PteFlags::RW).unwrap();
+        space.map_range(va(0x4000_0000)..va(0x4020_0000), pa(0x8000_0000), PteFlags::RW).unwrap();
+
+        space.protect(va(0x1000_1000)..va(0x1000_2000), PteFlags::READ).unwrap();
+        space.protect(va(0x4010_0000)..va(0x4010_1000), PteFlags::RX).unwrap();
+        assert_eq!(rwx(&space, 0x1000_0000), PteFlags::RW);
+        assert_eq!(rwx(&space, 0x1000_1000), PteFlags::READ);
+        assert_eq!(rwx(&space, 0x4010_0000), PteFlags::RX);
+        assert_eq!(rwx(&space, 0x4010_1000), PteFlags::RW);
+        assert_eq!(space.translate(va(0x4010_0008)), Some(pa(0x8010_0008)));
+        assert_eq!(space.owned_frames(), 3);
+
+        assert_eq!(
+            space.protect(va(0x1000_2000)..va(0x1000_4000), PteFlags::READ),
+            Err(VmError::NotMapped(va(0x1000_3000)))
+        );
+        assert_eq!(rwx(&space, 0x1000_2000), PteFlags::RW);
+        let unaligned = space.protect(va(0x1000_0800)..va(0x1000_1000), PteFlags::READ);
+        assert_eq!(unaligned, Err(VmError::Misaligned));
+        let no_rwx = space.protect(va(0x1000_0000)..va(0x1000_1000), PteFlags::USER);
+        assert_eq!(no_rwx, Err(VmError::BadFlags));
+        let write_only = space.protect(va(0x1000_0000)..va(0x1000_1000), PteFlags::WRITE);
+        assert_eq!(write_only, Err(VmError::BadFlags));
+
+        // No page to split a megapage with: an error, and the permissions stay
+        space.map_range(va(0x4020_0000)..va(0x4040_0000), pa(0x8020_0000), PteFlags::RW).unwrap();
+        let hog = take_all_pages();
+        let split = space.protect(va(0x4020_0000)..va(0x4030_0000), PteFlags::READ);
+        for p in hog {
+            page::dealloc(p);
+        }
+        assert_eq!(split, Err(VmError::OutOfMemory));
+        assert_eq!(rwx(&space, 0x4020_0000), PteFlags::RW);
+        assert_eq!(page::lookup(space.root(), va(0x4020_0000)).unwrap().level, 1);
+    }
+}